@@ -0,0 +1,33 @@
+#![feature(test)]
+
+extern crate test;
+extern crate md;
+
+use test::Bencher;
+
+use md::MarkdownParser;
+use md::parser::MarkdownConfig;
+
+fn document() -> String {
+    let mut s = String::new();
+    for i in 0..200 {
+        s.push_str(format!("# Section {}\n\nSome *emphasized* text with a [link](/u/{}) \
+                            and `code`, **strong** and _more_ text.\n\n\
+                            - item with ![image](/i.png)\n- item <b>html</b>\n\n\
+                            > quoted *text*\n\n", i, i).as_slice());
+    }
+    s
+}
+
+#[bench]
+fn full_parsing(b: &mut Bencher) {
+    let s = document();
+    b.iter(|| MarkdownParser::from_str(s.as_slice()).read_all());
+}
+
+#[bench]
+fn skipped_inline_parsing(b: &mut Bencher) {
+    let s = document();
+    let config = MarkdownConfig::default().skip_inline_parsing(true);
+    b.iter(|| MarkdownParser::from_str(s.as_slice()).with_config(config.clone()).read_all());
+}
@@ -1,13 +1,19 @@
-#![feature(unsafe_destructor, core, collections)]
+#![feature(unsafe_destructor, core, alloc, collections)]
 
-#[macro_use] extern crate log;
+extern crate core;
+extern crate alloc;
+extern crate collections;
+#[cfg(feature = "std")] #[macro_use] extern crate log;
 
 pub use tokens::*;
-pub use parser::MarkdownParser;
+pub use parser::{MarkdownParser, MarkdownEngine, ParseWarning, WarningKind, LineIndex,
+                 parse_document};
+pub use render::{render_html, to_html};
 
-mod util;
+#[macro_use] mod util;
 
 pub mod tokens;
 pub mod parser;
+pub mod render;
 
 
@@ -0,0 +1,156 @@
+use tokens::*;
+use util::{ByteSliceOps, to_text};
+
+// Splits trailing attribute block (`{#id .class key=val}`) off the line.
+// If there is no valid attribute block, the line is returned as is.
+pub fn split_attributes<'a>(line: &'a [u8]) -> (&'a [u8], Option<Attributes>) {
+    let trimmed = line.trim_right(b' ');
+    if !trimmed.ends_with(b"}") {
+        return (line, None);
+    }
+
+    let open = match trimmed.rposition_elem(&b'{') {
+        Some(idx) => idx,
+        None => return (line, None)
+    };
+
+    // attribute block should be separated from the content
+    if open > 0 && trimmed[open-1] != b' ' {
+        return (line, None);
+    }
+
+    match parse_attributes(&trimmed[open+1..trimmed.len()-1]) {
+        Some(attrs) => ((&trimmed[..open]).trim_right(b' '), Some(attrs)),
+        None => (line, None)
+    }
+}
+
+fn parse_attributes(buf: &[u8]) -> Option<Attributes> {
+    fn is_name_char(c: u8) -> bool {
+        match c {
+            b'a'...b'z' | b'A'...b'Z' | b'0'...b'9' | b'-' | b'_' | b':' => true,
+            _ => false
+        }
+    }
+
+    fn to_string(buf: &[u8]) -> String {
+        to_text(buf)
+    }
+
+    let mut attrs = Attributes::new();
+    let mut i = 0;
+    loop {
+        // skip whitespace between attributes
+        while i < buf.len() && buf[i] == b' ' { i += 1; }
+        if i == buf.len() { break; }
+
+        match buf[i] {
+            // #id or .class
+            c @ b'#' | c @ b'.' => {
+                i += 1;
+                let start = i;
+                while i < buf.len() && buf[i] != b' ' {
+                    if !is_name_char(buf[i]) { return None; }
+                    i += 1;
+                }
+                if start == i { return None; }
+
+                let name = to_string(&buf[start..i]);
+                if c == b'#' {
+                    attrs.id = Some(name);
+                } else {
+                    attrs.classes.push(name);
+                }
+            }
+
+            // key=value or key="value"
+            _ => {
+                let start = i;
+                while i < buf.len() && is_name_char(buf[i]) { i += 1; }
+                if start == i || i == buf.len() || buf[i] != b'=' { return None; }
+                let key = to_string(&buf[start..i]);
+                i += 1;
+
+                let value = if i < buf.len() && buf[i] == b'"' {
+                    i += 1;
+                    let start = i;
+                    while i < buf.len() && buf[i] != b'"' { i += 1; }
+                    if i == buf.len() { return None; }  // unterminated quote
+                    i += 1;
+                    to_string(&buf[start..i-1])
+                } else {
+                    let start = i;
+                    while i < buf.len() && buf[i] != b' ' {
+                        if one_of!(buf[i], b'"', b'{', b'}') { return None; }
+                        i += 1;
+                    }
+                    to_string(&buf[start..i])
+                };
+
+                match key.as_slice() {
+                    "id" => attrs.id = Some(value),
+                    "class" => attrs.classes.push(value),
+                    _ => attrs.data.push((key, value))
+                }
+            }
+        }
+    }
+
+    if attrs.is_empty() { None } else { Some(attrs) }
+}
+
+#[cfg(test)]
+mod tests {
+    use parser::{MarkdownParser, MarkdownConfig};
+    use tokens::*;
+
+    fn parse(s: &str, config: MarkdownConfig) -> Document {
+        MarkdownParser::from_str(s).with_config(config).read_all()
+    }
+
+    fn heading(level: usize, content: &str, attributes: Option<Attributes>) -> Block {
+        Heading {
+            level: level,
+            content: vec![Chunk(content.to_string())],
+            attributes: attributes
+        }
+    }
+
+    #[test]
+    fn heading_attributes() {
+        let doc = parse("## Title {#custom .note}\n", MarkdownConfig::default().attributes(true));
+        assert_eq!(doc, vec![heading(2, "Title", Some(Attributes {
+            id: Some("custom".to_string()),
+            classes: vec!["note".to_string()],
+            data: Vec::new()
+        }))]);
+    }
+
+    #[test]
+    fn fence_attributes() {
+        let doc = parse("```rust {.numbered linenostart=5}\nx\n```\n",
+                        MarkdownConfig::default().attributes(true));
+        assert_eq!(doc, vec![BlockCode {
+            tag: Some("rust".to_string()),
+            content: "x\n".to_string(),
+            attributes: Some(Attributes {
+                id: None,
+                classes: vec!["numbered".to_string()],
+                data: vec![("linenostart".to_string(), "5".to_string())]
+            })
+        }]);
+    }
+
+    #[test]
+    fn malformed_attributes_stay_literal() {
+        let config = MarkdownConfig::default().attributes(true);
+        assert_eq!(parse("# Title {#}\n", config.clone()), vec![heading(1, "Title {#}", None)]);
+        assert_eq!(parse("# Title{#id}\n", config), vec![heading(1, "Title{#id}", None)]);
+    }
+
+    #[test]
+    fn attributes_disabled() {
+        assert_eq!(parse("# Title {#id}\n", MarkdownConfig::default()),
+                   vec![heading(1, "Title {#id}", None)]);
+    }
+}
@@ -1,6 +1,6 @@
-use parser::{MarkdownParser, ParseResult, Success, End, NoParse};
+use parser::{MarkdownParser, MarkdownConfig, ParseResult, Success, End, NoParse};
 use tokens::*;
-use parser::inline::InlineParser;
+use parser::block::attributes::split_attributes;
 
 pub trait AtxHeadingParser {
     fn parse_atx_heading(&self) -> ParseResult<Block>;
@@ -11,6 +11,7 @@ impl<'a> AtxHeadingParser for MarkdownParser<'a> {
         debug!(">> trying atx header");
         parse_or_ret!(self.try_read_char(b'#'));
         self.cur.prev();
+        let m = self.cur.mark();
 
         // read and count hashes
         debug!(">> counting hashes");
@@ -24,14 +25,22 @@ impl<'a> AtxHeadingParser for MarkdownParser<'a> {
         }
         debug!(">> hashes: {}", level);
 
+        // hashes should be followed by a space or the line end, so `#foo` is
+        // a paragraph unless lenient headings are enabled; more than six
+        // hashes are a paragraph in any case
+        match self.cur.current_byte() {
+            Some(b' ') | Some(b'\t') | Some(b'\n') | None => {}
+            Some(b'#') => return NoParse,
+            Some(_) if self.config.lenient_atx => {}
+            Some(_) => return NoParse
+        }
+        m.cancel();
+
         // skip spaces after hashes
         // short-circuit if the document ends here
         debug!(">> skipping spaces");
         if self.skip_spaces().is_end() {
-            return Success(Heading {
-                level: level,
-                content: Vec::new()
-            });
+            return Success(make_heading(&*self.config, level, Vec::new(), None));
         }
 
         let pm = self.cur.phantom_mark();
@@ -39,34 +48,182 @@ impl<'a> AtxHeadingParser for MarkdownParser<'a> {
         // read the rest of the line
         debug!(">> reading rest of the line");
         self.read_line();
-        let buf = self.cur.slice_until_now_from(pm);  // without newline
+        let buf = self.cur.slice_to_now_from(pm);
+        // without newline, which may be absent at the end of the document
+        let buf = if buf.ends_with(b"\n") { &buf[..buf.len()-1] } else { buf };
         debug!(">> header line: {:?}", buf);
 
         debug!(">> skipping ending hashes and spaces");
-        // skip hashes and spaces backwards
+        // skip spaces and closing hashes backwards; the hashes should be
+        // separated from the content with a space, otherwise they are a part of it
         let mut n = buf.len();
-        while n > 0 {
-            match buf[n-1] {
-                b'#' => n -= 1,
-                _ => break
-            }
-        }
-        while n > 0 {
-            match buf[n-1] {
-                b' ' => n -= 1,
-                _ => break
-            }
-        }
+        while n > 0 && buf[n-1] == b' ' { n -= 1; }
+        let mut k = n;
+        while k > 0 && buf[k-1] == b'#' { k -= 1; }
+        if k == 0 || buf[k-1] == b' ' { n = k; }
+        while n > 0 && buf[n-1] == b' ' { n -= 1; }
+
+        // split off trailing attribute block, if enabled
+        let (buf, attributes) = if self.config.attributes {
+            split_attributes(&buf[..n])
+        } else {
+            (&buf[..n], None)
+        };
 
         debug!(">> parsing header inline content");
         // parse header contents
-        let subp = self.fork(&buf[..n]);
-        let result = self.fix_links(subp.parse_inline());
+        let result = self.parse_heading_inline(buf, pm, self.cur.phantom_mark());
         debug!(">> parsed: {:?}", result);
 
-        Success(Heading {
-            level: level,
-            content: result
-        })
+        Success(make_heading(&*self.config, level, result, attributes))
+    }
+}
+
+// applies heading level offset and generates an id if requested
+pub fn make_heading(config: &MarkdownConfig, level: usize, content: Text,
+                    attributes: Option<Attributes>) -> Block {
+    let mut attributes = attributes;
+    if config.heading_ids && attributes.as_ref().map(|a| a.id.is_none()).unwrap_or(true) {
+        let slug = slugify(&content, config.transliterate_heading_ids);
+        if !slug.is_empty() {
+            let mut a = attributes.unwrap_or_else(Attributes::new);
+            a.id = Some(slug);
+            attributes = Some(a);
+        }
+    }
+
+    Heading {
+        level: ::std::cmp::min(level + config.heading_offset, 6),
+        content: content,
+        attributes: attributes
+    }
+}
+
+// lowercased alphanumeric characters of the text with whitespace runs
+// replaced by dashes, like GitHub heading anchors; accented latin letters
+// are optionally replaced with their ASCII counterparts, other non-ASCII
+// characters are percent-encoded
+fn slugify(content: &Text, transliterate: bool) -> String {
+    let mut text = String::new();
+    plain_text(&mut text, content);
+
+    let mut slug = String::new();
+    let mut dash = false;
+    for c in text.as_slice().chars() {
+        if c.is_alphanumeric() || c == '-' || c == '_' {
+            if dash && !slug.is_empty() { slug.push('-'); }
+            dash = false;
+            let c = c.to_lowercase();
+            match transliterate_char(c) {
+                Some(s) if transliterate => slug.push_str(s),
+                _ if (c as u32) < 0x80 => slug.push(c),
+                _ => for b in c.to_string().as_bytes().iter() {
+                    slug.push_str(format!("%{:02X}", b).as_slice());
+                }
+            }
+        } else if c.is_whitespace() {
+            dash = true;
+        }
+    }
+    slug
+}
+
+// ASCII replacements of lowercase letters of Latin-1 Supplement and Latin Extended-A
+fn transliterate_char(c: char) -> Option<&'static str> {
+    Some(match c {
+        'à' | 'á' | 'â' | 'ã' | 'ä' | 'å' | 'ā' | 'ă' | 'ą' => "a",
+        'æ' => "ae",
+        'ç' | 'ć' | 'ĉ' | 'ċ' | 'č' => "c",
+        'ð' | 'ď' | 'đ' => "d",
+        'è' | 'é' | 'ê' | 'ë' | 'ē' | 'ĕ' | 'ė' | 'ę' | 'ě' => "e",
+        'ĝ' | 'ğ' | 'ġ' | 'ģ' => "g",
+        'ĥ' | 'ħ' => "h",
+        'ì' | 'í' | 'î' | 'ï' | 'ĩ' | 'ī' | 'ĭ' | 'į' | 'ı' => "i",
+        'ĳ' => "ij",
+        'ĵ' => "j",
+        'ķ' | 'ĸ' => "k",
+        'ĺ' | 'ļ' | 'ľ' | 'ŀ' | 'ł' => "l",
+        'ñ' | 'ń' | 'ņ' | 'ň' | 'ŉ' | 'ŋ' => "n",
+        'ò' | 'ó' | 'ô' | 'õ' | 'ö' | 'ø' | 'ō' | 'ŏ' | 'ő' => "o",
+        'œ' => "oe",
+        'ŕ' | 'ŗ' | 'ř' => "r",
+        'ś' | 'ŝ' | 'ş' | 'š' | 'ſ' => "s",
+        'ß' => "ss",
+        'ţ' | 'ť' | 'ŧ' => "t",
+        'þ' => "th",
+        'ù' | 'ú' | 'û' | 'ü' | 'ũ' | 'ū' | 'ŭ' | 'ů' | 'ű' | 'ų' => "u",
+        'ŵ' => "w",
+        'ý' | 'ÿ' | 'ŷ' => "y",
+        'ź' | 'ż' | 'ž' => "z",
+        _ => return None
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use parser::{MarkdownParser, MarkdownConfig};
+    use tokens::*;
+
+    fn parse(s: &str) -> Document {
+        MarkdownParser::from_str(s).read_all()
+    }
+
+    fn heading(level: usize, content: &str) -> Block {
+        let content = if content.is_empty() { vec![] } else { vec![Chunk(content.to_string())] };
+        Heading { level: level, content: content, attributes: None }
+    }
+
+    #[test]
+    fn closing_hashes_need_a_space() {
+        assert_eq!(parse("# foo #\n"), vec![heading(1, "foo")]);
+        assert_eq!(parse("# foo#\n"), vec![heading(1, "foo#")]);
+        assert_eq!(parse("## foo ##   \n"), vec![heading(2, "foo")]);
+    }
+
+    #[test]
+    fn empty_headings() {
+        assert_eq!(parse("###\n"), vec![heading(3, "")]);
+        assert_eq!(parse("## ##\n"), vec![heading(2, "")]);
+        assert_eq!(parse("#"), vec![heading(1, "")]);
+    }
+
+    fn heading_id(s: &str, transliterate: bool) -> Option<String> {
+        let config = MarkdownConfig::default().heading_ids(true).transliterate_heading_ids(transliterate);
+        match MarkdownParser::from_str(s).with_config(config).read_all().pop() {
+            Some(Heading { attributes: Some(attributes), .. }) => attributes.id,
+            _ => None
+        }
+    }
+
+    #[test]
+    fn transliterated_ids() {
+        assert_eq!(heading_id("# Café", true), Some("cafe".to_string()));
+        assert_eq!(heading_id("# Straße Ölmühle", true), Some("strasse-olmuhle".to_string()));
+    }
+
+    #[test]
+    fn percent_encoded_ids() {
+        assert_eq!(heading_id("# Café", false), Some("caf%C3%A9".to_string()));
+        assert_eq!(heading_id("# Привет мир", true), Some("%D0%BF%D1%80%D0%B8%D0%B2%D0%B5%D1%82-\
+                                                        %D0%BC%D0%B8%D1%80".to_string()));
+    }
+
+    #[test]
+    fn space_after_hashes() {
+        assert_eq!(parse("#foo\n"), vec![Paragraph(vec![Chunk("#foo".to_string())])]);
+        assert_eq!(parse("#######\n"), vec![Paragraph(vec![Chunk("#######".to_string())])]);
+    }
+
+    #[test]
+    fn lenient_atx() {
+        let config = MarkdownConfig::default().lenient_atx(true);
+        assert_eq!(MarkdownParser::from_str("#foo\n").with_config(config.clone()).read_all(),
+                   vec![heading(1, "foo")]);
+        assert_eq!(MarkdownParser::from_str("##foo ##\n").with_config(config.clone()).read_all(),
+                   vec![heading(2, "foo")]);
+        assert_eq!(MarkdownParser::from_str("#######\n").with_config(config.clone()).read_all(),
+                   vec![Paragraph(vec![Chunk("#######".to_string())])]);
+        assert_eq!(MarkdownParser::from_str("#######foo\n").with_config(config).read_all(),
+                   vec![Paragraph(vec![Chunk("#######foo".to_string())])]);
     }
 }
@@ -1,25 +1,31 @@
 use parser::{MarkdownParser, ParseResult, Success, End, NoParse};
 use tokens::*;
+use util::to_text;
 
 pub trait BlockCodeParser {
     fn parse_block_code(&self) -> ParseResult<Block>;
 }
 
 trait Ops {
-    fn block_code_prefix(&self) -> ParseResult<()>;
+    fn block_code_prefix(&self) -> ParseResult<usize>;
 }
 
 impl<'a> Ops for MarkdownParser<'a> {
-    fn block_code_prefix(&self) -> ParseResult<()> {
+    // returns the number of columns of a tab which are left after the prefix
+    fn block_code_prefix(&self) -> ParseResult<usize> {
+        let tab_width = self.config.tab_width;
         let mut n = 0usize;
         let m = self.cur.mark();
-        loop {
-            if n == 4 { break };
-            parse_or_ret!(self.try_read_char(b' '));
-            n += 1
+        while n < 4 {
+            match self.cur.next_byte() {
+                Some(b' ') => n += 1,
+                Some(b'\t') => n += tab_width - n % tab_width,
+                Some(_) => return NoParse,
+                None => return End
+            }
         }
         m.cancel();
-        Success(())
+        Success(n - 4)
     }
 }
 
@@ -31,7 +37,11 @@ impl<'a> BlockCodeParser for MarkdownParser<'a> {
         parse_or_ret!(self.block_code_prefix());
         m.reset();
 
+        // trailing blank lines are not a part of the block, so the content
+        // and the cursor are cut after the last non-blank line
         let mut buf = Vec::new();
+        let mut content_end = 0;
+        let mut pm_end = self.cur.phantom_mark();
         loop {
             match self.block_code_prefix() {
                 NoParse => {  // no prefix, check for emptiness
@@ -45,11 +55,54 @@ impl<'a> BlockCodeParser for MarkdownParser<'a> {
                 }
                 End => break,
                 // prefix is ok, read everything else
-                _ => { parse_or_break!(self.read_line_to(&mut buf)); }
+                Success(rest) => {
+                    if !self.cur.available() { break; }
+                    let line_start = buf.len();
+                    for _ in 0..rest { buf.push(b' '); }
+                    parse_or_break!(self.read_line_to(&mut buf));
+                    if buf[line_start..].iter().any(|&c| !one_of!(c, b' ', b'\t', b'\n')) {
+                        content_end = buf.len();
+                        pm_end = self.cur.phantom_mark();
+                    }
+                }
             }
         }
+        // a block without non-blank lines is kept as is
+        if content_end > 0 {
+            buf.truncate(content_end);
+            self.cur.pos.set(pm_end.pos);
+        }
+
+        Success(BlockCode {
+            tag: None,
+            content: to_text(buf.as_slice()),
+            attributes: None
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use parser::MarkdownParser;
+    use tokens::*;
+
+    fn parse(s: &str) -> Document {
+        MarkdownParser::from_str(s).read_all()
+    }
+
+    fn code(content: &str) -> Block {
+        BlockCode { tag: None, content: content.to_string(), attributes: None }
+    }
+
+    #[test]
+    fn interior_blank_lines_are_kept() {
+        assert_eq!(parse("    a\n\n\n    b\n      \n    c\n"), vec![code("a\n\n\nb\n  \nc\n")]);
+    }
 
-        // TODO: handle UTF-8 decoding error
-        Success(BlockCode { tag: None, content: String::from_utf8(buf).unwrap() })
+    #[test]
+    fn trailing_blank_lines_are_stripped() {
+        assert_eq!(parse("    a\n\n    \n\nb\n"),
+                   vec![code("a\n"), Paragraph(vec![Chunk("b".to_string())])]);
+        assert_eq!(parse("    a\n\n\n"), vec![code("a\n")]);
     }
 }
@@ -1,4 +1,6 @@
-use parser::{MarkdownParser, ParseResult, Success, End, NoParse};
+use std::iter::repeat;
+
+use parser::{MarkdownParser, ParseResult, Success, End, NoParse, NestingLimitExceeded};
 use tokens::*;
 
 pub trait BlockQuoteParser {
@@ -6,38 +8,81 @@ pub trait BlockQuoteParser {
 }
 
 trait Ops {
-    fn block_quote_prefix(&self) -> ParseResult<()>;
+    fn block_quote_prefix(&self) -> ParseResult<(usize, usize)>;
 }
 
 impl<'a> Ops for MarkdownParser<'a> {
-    fn block_quote_prefix(&self) -> ParseResult<()> {
+    // returns the number of spaces which the content starts with in place of
+    // tabs after the marker and the source column of the content
+    fn block_quote_prefix(&self) -> ParseResult<(usize, usize)> {
+        let line_start = self.cur.pos.get();
         parse_or_ret!(self.try_skip_initial_spaces());
         parse_or_ret!(self.try_read_char(b'>'));
-        self.try_read_char(b' ');
-        Success(())
+        if self.cur.current_byte() != Some(b'\t') {
+            // the space after the marker is optional, `>text` is a block quote too
+            self.try_read_char(b' ');
+            return Success((0, self.column + self.cur.pos.get() - line_start));
+        }
+
+        // tabs are expanded from the line start in the source, the first column
+        // of the expansion is the space after the marker
+        let tab_width = self.config.tab_width;
+        let marker_end = self.column + self.cur.pos.get() - line_start;
+        let mut column = marker_end;
+        loop {
+            match self.cur.current_byte() {
+                Some(b' ') => column += 1,
+                Some(b'\t') => column += tab_width - column % tab_width,
+                _ => break
+            }
+            self.cur.next();
+        }
+        Success((column - marker_end - 1, marker_end + 1))
     }
 }
 
 impl<'a> BlockQuoteParser for MarkdownParser<'a> {
     fn parse_block_quote(&self) -> ParseResult<Block> {
         debug!(">> trying blockquote");
-
+        let start = self.cur.phantom_mark();
         let m = self.cur.mark();
         parse_or_ret!(self.block_quote_prefix());
         m.reset();
 
+        if self.nesting_exceeded() {
+            self.warn(NestingLimitExceeded, self.source_range(start),
+                      "block quote nested too deeply".to_string());
+            return NoParse;
+        }
+
         let mut buf = Vec::new();
+        let mut column = self.column;
         loop {
-            break_on_end!(self.block_quote_prefix());
+            // lines without the prefix are lazy continuation lines unless it is disabled
+            let m = self.cur.mark();
+            match self.block_quote_prefix() {
+                End => break,
+                NoParse if self.config.strict_blockquotes => break,
+                NoParse => m.cancel(),
+                Success((n, c)) => {
+                    m.cancel();
+                    // nested quotes expand tabs from the column of the first line
+                    if buf.is_empty() { column = c; }
+                    buf.extend(repeat(b' ').take(n));
+                }
+            }
             parse_or_break!(self.read_line_to(&mut buf));
 
-            // break if there is an empty line followed by non-quote line after this line
+            // an empty line ends the quote, unless adjacent quotes are merged
+            // and the next non-empty line is quoted too
             match self.try_parse_empty_line() {
                 Success(_) => {
-                    let mut _m = self.cur.mark();
+                    if !self.config.merge_adjacent_blockquotes { break; }
+                    while self.try_parse_empty_line().is_success() {}
+                    let _m = self.cur.mark();
                     match self.block_quote_prefix() {
                         NoParse | End => break,
-                        _ => {}
+                        _ => buf.push(b'\n')
                     }
                 }
                 End => break,
@@ -45,10 +90,78 @@ impl<'a> BlockQuoteParser for MarkdownParser<'a> {
             }
         }
 
-        let subp = self.fork(buf.as_slice());
+        let mut subp = self.fork_container(buf.as_slice(), start, self.cur.phantom_mark());
+        subp.column = column;
         let result = self.fix_links(subp.read_all());
 
         Success(BlockQuote(result))
     }
 
 }
+
+#[cfg(test)]
+mod tests {
+    use parser::{MarkdownParser, MarkdownConfig};
+    use tokens::*;
+
+    fn parse(s: &str, config: MarkdownConfig) -> Document {
+        MarkdownParser::from_str(s).with_config(config).read_all()
+    }
+
+    fn paragraph(s: &str) -> Block { Paragraph(vec![Chunk(s.to_string())]) }
+
+    #[test]
+    fn lazy_continuation() {
+        assert_eq!(parse("> a\nb\n", MarkdownConfig::default()),
+                   vec![BlockQuote(vec![paragraph("a\nb")])]);
+    }
+
+    #[test]
+    fn strict_blockquotes() {
+        assert_eq!(parse("> a\nb\n", MarkdownConfig::default().strict_blockquotes(true)),
+                   vec![BlockQuote(vec![paragraph("a")]), paragraph("b")]);
+    }
+
+    #[test]
+    fn marker_without_space() {
+        assert_eq!(parse(">x\n", MarkdownConfig::default()), vec![BlockQuote(vec![paragraph("x")])]);
+        assert_eq!(parse(">x\n> y\n", MarkdownConfig::default()),
+                   vec![BlockQuote(vec![paragraph("x\ny")])]);
+    }
+
+    #[test]
+    fn consecutive_markers() {
+        let nested = BlockQuote(vec![BlockQuote(vec![BlockQuote(vec![paragraph("x")])])]);
+        assert_eq!(parse(">>> x\n", MarkdownConfig::default()), vec![nested.clone()]);
+        assert_eq!(parse("> > >x\n", MarkdownConfig::default()), vec![nested]);
+    }
+
+    #[test]
+    fn tab_after_marker() {
+        assert_eq!(parse(">\tfoo\n", MarkdownConfig::default()),
+                   vec![BlockQuote(vec![paragraph("foo")])]);
+        assert_eq!(parse(">\t\tfoo\n", MarkdownConfig::default()), vec![BlockQuote(vec![BlockCode {
+            tag: None,
+            content: "  foo\n".to_string(),
+            attributes: None
+        }])]);
+        // the inner marker is at the third column of the source line
+        assert_eq!(parse("> >\t\tfoo\n", MarkdownConfig::default()),
+                   vec![BlockQuote(vec![BlockQuote(vec![BlockCode {
+                       tag: None,
+                       content: "foo\n".to_string(),
+                       attributes: None
+                   }])])]);
+    }
+
+    #[test]
+    fn adjacent_quotes() {
+        let source = "> a\n\n> b\n\nc\n";
+        assert_eq!(parse(source, MarkdownConfig::default()), vec![
+            BlockQuote(vec![paragraph("a")]), BlockQuote(vec![paragraph("b")]), paragraph("c")
+        ]);
+        assert_eq!(parse(source, MarkdownConfig::default().merge_adjacent_blockquotes(true)), vec![
+            BlockQuote(vec![paragraph("a"), paragraph("b")]), paragraph("c")
+        ]);
+    }
+}
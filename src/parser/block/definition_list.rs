@@ -0,0 +1,157 @@
+use parser::{MarkdownParser, ParseResult, PhantomMark, Success, NoParse};
+use parser::inline::InlineParser;
+use tokens::*;
+use util::ByteSliceOps;
+
+pub trait DefinitionListParser {
+    fn parse_definition_list(&self) -> ParseResult<Block>;
+}
+
+trait Ops {
+    fn parse_term(&self) -> Option<Text>;
+    fn try_read_definition_marker(&self) -> bool;
+    fn parse_definition(&self, start: PhantomMark) -> Document;
+}
+
+impl<'a> Ops for MarkdownParser<'a> {
+    // a single line followed by a definition
+    fn parse_term(&self) -> Option<Text> {
+        let m = self.cur.mark();
+        let pm = self.cur.phantom_mark();
+        parse_or_ret_none!(self.read_line());
+        let line = self.cur.slice_to_now_from(pm).trim_right_one(b'\n');
+        if line.iter().all(|&c| c == b' ' || c == b'\t') { return None; }
+
+        {
+            let _m = self.cur.mark();
+            if !self.try_read_definition_marker() { return None; }
+        }
+        m.cancel();
+
+        let subp = self.fork_container(line.trim_left(b' '), pm, self.cur.phantom_mark());
+        Some(self.fix_links(subp.parse_inline()))
+    }
+
+    // up to three spaces of indentation, a colon and a space
+    fn try_read_definition_marker(&self) -> bool {
+        let m = self.cur.mark();
+        if !self.try_skip_initial_spaces().is_success() { return false; }
+        if !self.try_read_char(b':').is_success() { return false; }
+        if !self.try_read_char(b' ').is_success() { return false; }
+        m.cancel();
+        true
+    }
+
+    // the rest of the marker line and the following lines indented by four spaces
+    fn parse_definition(&self, start: PhantomMark) -> Document {
+        self.skip_spaces();
+        let mut buf = Vec::new();
+        self.read_line_to(&mut buf);
+
+        let mut last_blank = false;
+        let mut pm_blank = self.cur.phantom_mark();
+        loop {
+            let pm = self.cur.phantom_mark();
+            match self.try_parse_empty_line() {
+                Success(_) => {
+                    if !last_blank { pm_blank = pm; }
+                    buf.push(b'\n');
+                    last_blank = true;
+                    continue
+                }
+                NoParse => {}
+                _ => break
+            }
+
+            if !self.lookahead_chars(4, b' ') { break; }
+            self.cur.advance(4);
+            self.read_line_to(&mut buf);
+            last_blank = false;
+        }
+
+        // trailing empty lines do not belong to the definition
+        if last_blank {
+            self.cur.pos.set(pm_blank.pos);
+        }
+
+        let subp = self.fork_container(buf.as_slice(), start, self.cur.phantom_mark());
+        self.fix_links(subp.read_all())
+    }
+}
+
+impl<'a> DefinitionListParser for MarkdownParser<'a> {
+    fn parse_definition_list(&self) -> ParseResult<Block> {
+        debug!(">> trying definition list");
+        if !self.config.definition_lists || self.nesting_exceeded() { return NoParse; }
+
+        let start = self.cur.phantom_mark();
+        let mut items = Vec::new();
+        loop {
+            // items may be separated by empty lines, as may the definitions of a term,
+            // but the first definition marker should be on the line after its term
+            let m = self.cur.mark();
+            if !items.is_empty() {
+                while self.try_parse_empty_line().is_success() {}
+            }
+            let term = match self.parse_term() {
+                Some(term) => term,
+                None => break
+            };
+            m.cancel();
+
+            let mut definitions = Vec::new();
+            loop {
+                let m = self.cur.mark();
+                if !definitions.is_empty() {
+                    while self.try_parse_empty_line().is_success() {}
+                }
+                if !self.try_read_definition_marker() { break; }
+                m.cancel();
+                definitions.push(self.parse_definition(start));
+            }
+
+            items.push((term, definitions));
+        }
+
+        if items.is_empty() { NoParse } else { Success(DefinitionList(items)) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use parser::{MarkdownParser, MarkdownConfig};
+    use tokens::*;
+
+    fn parse(s: &str) -> Document {
+        MarkdownParser::from_str(s).with_config(MarkdownConfig::default().definition_lists(true))
+            .read_all()
+    }
+
+    fn paragraph(s: &str) -> Block {
+        Paragraph(vec![Chunk(s.to_string())])
+    }
+
+    #[test]
+    fn inline_terms() {
+        assert_eq!(parse("*Term* and `code`\n: def\n"), vec![DefinitionList(vec![(
+            vec![Emphasis(vec![Chunk("Term".to_string())]), Chunk(" and ".to_string()),
+                 Code("code".to_string())],
+            vec![vec![paragraph("def")]]
+        )])]);
+    }
+
+    #[test]
+    fn multi_paragraph_definitions() {
+        let source = "a\n: first\n\n    second\n\n: other\n\nb\n: third\n";
+        assert_eq!(parse(source), vec![DefinitionList(vec![
+            (vec![Chunk("a".to_string())], vec![vec![paragraph("first"), paragraph("second")],
+                                                vec![paragraph("other")]]),
+            (vec![Chunk("b".to_string())], vec![vec![paragraph("third")]])
+        ])]);
+    }
+
+    #[test]
+    fn marker_follows_term() {
+        assert_eq!(parse("a\n\n: b\n"), vec![paragraph("a"), paragraph(": b")]);
+    }
+}
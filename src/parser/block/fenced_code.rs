@@ -0,0 +1,154 @@
+use std::str;
+
+use parser::{MarkdownParser, ParseResult, Success, End, NoParse, UnterminatedFence};
+use parser::block::attributes::split_attributes;
+use tokens::*;
+use util::{ByteSliceOps, to_text};
+
+pub trait FencedCodeParser {
+    fn parse_fenced_code(&self) -> ParseResult<Block>;
+}
+
+trait Ops {
+    fn skip_fence_indent(&self, max: usize) -> usize;
+    fn try_parse_fence_closing(&self, fc: u8, n: usize) -> ParseResult<()>;
+}
+
+impl<'a> Ops for MarkdownParser<'a> {
+    fn skip_fence_indent(&self, max: usize) -> usize {
+        let mut n = 0;
+        while n < max && self.cur.current_byte() == Some(b' ') {
+            self.cur.next();
+            n += 1;
+        }
+        n
+    }
+
+    fn try_parse_fence_closing(&self, fc: u8, n: usize) -> ParseResult<()> {
+        let m = self.cur.mark();
+        self.skip_fence_indent(3);
+
+        let mut cn = 0;
+        while self.cur.current_byte() == Some(fc) {
+            self.cur.next();
+            cn += 1;
+        }
+        if cn < n { return NoParse; }
+
+        // only spaces are allowed after closing fence
+        loop {
+            match self.cur.next_byte() {
+                Some(b' ') => {}
+                Some(b'\n') | None => break,
+                Some(_) => return NoParse
+            }
+        }
+
+        m.cancel();
+        Success(())
+    }
+}
+
+impl<'a> FencedCodeParser for MarkdownParser<'a> {
+    fn parse_fenced_code(&self) -> ParseResult<Block> {
+        debug!(">> trying fenced code block");
+        let start = self.cur.phantom_mark();
+        let m = self.cur.mark();
+
+        let indent = self.skip_fence_indent(4);
+        if indent == 4 { return NoParse; }
+
+        // read fence characters
+        let fc = match self.cur.current_byte() {
+            Some(c) if one_of!(c, b'`', b'~') => c,
+            Some(_) => return NoParse,
+            None => return End
+        };
+        let mut n = 0;
+        while self.cur.current_byte() == Some(fc) {
+            self.cur.next();
+            n += 1;
+        }
+        if n < 3 { return NoParse; }
+
+        // the rest of the line is an info string
+        let pm = self.cur.phantom_mark();
+        self.read_line();
+        let info = self.cur.slice_to_now_from(pm).trim_right_one(b'\n')
+            .trim_left(b' ').trim_right(b' ');
+        debug!(">> fence info string: {}", str::from_utf8(info).unwrap());
+
+        // backtick fences cannot have backticks in info strings
+        if fc == b'`' && info.contains(&b'`') { return NoParse; }
+
+        let (info, attributes) = if self.config.attributes {
+            split_attributes(info)
+        } else {
+            (info, None)
+        };
+
+        let mut buf = Vec::new();
+        let mut closed = false;
+        while self.cur.available() {
+            if self.try_parse_fence_closing(fc, n).is_success() {
+                closed = true;
+                break;
+            }
+
+            // strip the indentation of the opening fence from content lines
+            self.skip_fence_indent(indent);
+            parse_or_break!(self.read_line_to(&mut buf));
+        }
+
+        m.cancel();
+
+        if !closed {
+            self.warn(UnterminatedFence, self.source_range(start), "unterminated code fence".to_string());
+        }
+
+        let tag = if info.is_empty() {
+            None
+        } else {
+            Some(to_text(info))
+        };
+
+        Success(BlockCode {
+            tag: tag,
+            content: to_text(buf.as_slice()),
+            attributes: attributes
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use parser::MarkdownParser;
+    use tokens::*;
+
+    fn parse(s: &str) -> Document {
+        MarkdownParser::from_str(s).read_all()
+    }
+
+    fn code(content: &str) -> Block {
+        BlockCode { tag: None, content: content.to_string(), attributes: None }
+    }
+
+    #[test]
+    fn whitespace_only_line() {
+        assert_eq!(parse("```\na\n   \nb\n```\n"), vec![code("a\n   \nb\n")]);
+    }
+
+    #[test]
+    fn whitespace_only_line_in_list_item() {
+        assert_eq!(parse("- ```\n  a\n     \n  b\n  ```\n"), vec![UnorderedList {
+            items: vec![vec![code("a\n   \nb\n")]],
+            tight: true
+        }]);
+    }
+
+    #[test]
+    fn fence_indentation_is_stripped() {
+        assert_eq!(parse("  ```\n    a\n  b\n c\n  ```\n"), vec![code("  a\nb\nc\n")]);
+        assert_eq!(parse("   ```\n      a\n   ```\n"), vec![code("   a\n")]);
+    }
+}
@@ -0,0 +1,80 @@
+use parser::{MarkdownParser, ParseResult, Success, End, NoParse, NestingLimitExceeded};
+use tokens::*;
+use util::{to_text, CharOps};
+
+pub trait FootnoteParser {
+    fn parse_footnote_definition(&self) -> ParseResult<Block>;
+}
+
+impl<'a> FootnoteParser for MarkdownParser<'a> {
+    fn parse_footnote_definition(&self) -> ParseResult<Block> {
+        debug!(">> trying footnote definition");
+        if !self.config.footnotes { return NoParse; }
+
+        let start = self.cur.phantom_mark();
+        let m = self.cur.mark();
+        parse_or_ret!(self.try_skip_initial_spaces());
+        parse_or_ret!(self.try_read_char(b'['));
+        parse_or_ret!(self.try_read_char(b'^'));
+
+        // label may not contain spaces or brackets
+        let pm = self.cur.phantom_mark();
+        loop {
+            match opt_ret_end!(self.cur.next_byte()) {
+                b']' => break,
+                c if c.is_space() || c == b'\n' || c == b'[' => return NoParse,
+                _ => {}
+            }
+        }
+        let label = self.cur.slice_until_now_from(pm);
+        if label.is_empty() { return NoParse; }
+        parse_or_ret!(self.try_read_char(b':'));
+        self.skip_spaces();
+
+        if self.nesting_exceeded() {
+            self.warn(NestingLimitExceeded, self.source_range(start),
+                      "footnote definition nested too deeply".to_string());
+            return NoParse;
+        }
+
+        let mut buf = Vec::new();
+        self.read_line_to(&mut buf);
+
+        // the definition continues with lines indented by four spaces
+        let mut last_blank = false;
+        let mut pm_blank = self.cur.phantom_mark();
+        loop {
+            let pm = self.cur.phantom_mark();
+            match self.try_parse_empty_line() {
+                Success(_) => {
+                    if !last_blank { pm_blank = pm; }
+                    buf.push(b'\n');
+                    last_blank = true;
+                    continue
+                }
+                End => break,
+                NoParse => {}
+            }
+
+            if !self.lookahead_chars(4, b' ') { break; }
+            self.cur.advance(4);
+            self.read_line_to(&mut buf);
+            last_blank = false;
+        }
+
+        // trailing empty lines do not belong to the definition
+        if last_blank {
+            self.cur.pos.set(pm_blank.pos);
+        }
+
+        m.cancel();
+
+        let subp = self.fork_container(buf.as_slice(), start, self.cur.phantom_mark());
+        let content = self.fix_links(subp.read_all());
+
+        Success(FootnoteDefinition {
+            id: to_text(label),
+            content: content
+        })
+    }
+}
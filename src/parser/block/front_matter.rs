@@ -0,0 +1,120 @@
+use parser::{MarkdownParser, ParseResult, Success, NoParse};
+use tokens::*;
+use util::to_text;
+
+pub trait FrontMatterParser {
+    fn parse_front_matter(&self) -> ParseResult<Block>;
+}
+
+trait Ops {
+    fn try_read_fence_line(&self, fence: &[u8]) -> bool;
+}
+
+impl<'a> Ops for MarkdownParser<'a> {
+    // the fence may only be followed by spaces
+    fn try_read_fence_line(&self, fence: &[u8]) -> bool {
+        let m = self.cur.mark();
+        for &c in fence.iter() {
+            if self.cur.next_byte() != Some(c) { return false; }
+        }
+        loop {
+            match self.cur.next_byte() {
+                Some(b' ') => {}
+                Some(b'\n') | None => break,
+                Some(_) => return false
+            }
+        }
+        m.cancel();
+        true
+    }
+}
+
+impl<'a> FrontMatterParser for MarkdownParser<'a> {
+    fn parse_front_matter(&self) -> ParseResult<Block> {
+        let format = match self.config.front_matter {
+            Some(format) => format,
+            None => return NoParse
+        };
+        // front matter is recognized only at the very beginning of the document
+        if self.depth > 0 || self.cur.pos.get() > 0 { return NoParse; }
+
+        debug!(">> trying front matter");
+        let fence: &[u8] = match format {
+            Yaml => b"---",
+            Toml => b"+++"
+        };
+
+        let m = self.cur.mark();
+        if !self.try_read_fence_line(fence) { return NoParse; }
+
+        let pm = self.cur.phantom_mark();
+        let content;
+        loop {
+            // unclosed front matter is parsed as usual
+            if !self.cur.available() { return NoParse; }
+
+            let pm_line = self.cur.phantom_mark();
+            if self.try_read_fence_line(fence) || format == Yaml && self.try_read_fence_line(b"...") {
+                content = self.cur.slice(pm, pm_line);
+                break;
+            }
+            self.read_line();
+        }
+
+        m.cancel();
+        Success(FrontMatter {
+            format: format,
+            content: to_text(content)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use parser::{MarkdownParser, MarkdownConfig};
+    use tokens::*;
+
+    fn parse(s: &str, format: Option<FrontMatterFormat>) -> Document {
+        MarkdownParser::from_str(s).with_config(MarkdownConfig::default().front_matter(format))
+            .read_all()
+    }
+
+    fn front_matter(format: FrontMatterFormat, content: &str) -> Block {
+        FrontMatter { format: format, content: content.to_string() }
+    }
+
+    fn paragraph(s: &str) -> Block { Paragraph(vec![Chunk(s.to_string())]) }
+
+    #[test]
+    fn yaml() {
+        assert_eq!(parse("---\ntitle: a\ntags: [b]\n---\ntext\n", Some(Yaml)),
+                   vec![front_matter(Yaml, "title: a\ntags: [b]\n"), paragraph("text")]);
+        assert_eq!(parse("---\ntitle: a\n...\n", Some(Yaml)),
+                   vec![front_matter(Yaml, "title: a\n")]);
+    }
+
+    #[test]
+    fn toml() {
+        assert_eq!(parse("+++\ntitle = \"a\"\n+++\ntext\n", Some(Toml)),
+                   vec![front_matter(Toml, "title = \"a\"\n"), paragraph("text")]);
+    }
+
+    #[test]
+    fn only_at_the_beginning() {
+        let doc = parse("\n---\ntitle: a\n---\n", Some(Yaml));
+        assert_eq!(doc[0], HorizontalRule);
+        assert_eq!(parse("text\n\n+++\na\n+++\n", Some(Toml))[0], paragraph("text"));
+    }
+
+    #[test]
+    fn disabled() {
+        let doc = parse("---\ntitle: a\n---\n", None);
+        assert_eq!(doc[0], HorizontalRule);
+        assert!(match doc[1] { Heading { level: 2, .. } => true, _ => false });
+    }
+
+    #[test]
+    fn unclosed() {
+        assert_eq!(parse("---\ntitle: a\n", Some(Yaml))[0], HorizontalRule);
+    }
+}
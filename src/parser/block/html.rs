@@ -0,0 +1,211 @@
+use std::ascii::AsciiExt;
+
+use parser::{MarkdownParser, ParseResult, Success, End, NoParse, DisallowedHtml};
+use tokens::*;
+use util::to_text;
+
+use self::HtmlBlockEnd::*;
+
+pub trait HtmlBlockParser {
+    fn parse_html_block(&self) -> ParseResult<Block>;
+}
+
+// block-level tags which start an HTML block ending at the next empty line
+static BLOCK_TAGS: &'static [&'static str] = &[
+    "address", "article", "aside", "blockquote", "body", "caption", "center",
+    "dd", "details", "dialog", "div", "dl", "dt", "fieldset", "figcaption",
+    "figure", "footer", "form", "h1", "h2", "h3", "h4", "h5", "h6", "head",
+    "header", "hr", "html", "iframe", "legend", "li", "main", "menu", "nav",
+    "ol", "p", "section", "summary", "table", "tbody", "td", "tfoot", "th",
+    "thead", "title", "tr", "ul"
+];
+
+// tags whose content is raw and which end the block only at the closing tag
+static RAW_TAGS: &'static [&'static str] = &["script", "pre", "style"];
+
+#[derive(Copy, PartialEq, Eq)]
+enum HtmlBlockEnd {
+    CommentEnd,
+    ClosingTag(&'static str),
+    EmptyLine
+}
+
+trait Ops {
+    fn html_block_start(&self, line: &[u8]) -> Option<HtmlBlockEnd>;
+    fn parse_html_block_contents(&self) -> ParseResult<String>;
+}
+
+impl<'a> Ops for MarkdownParser<'a> {
+    fn html_block_start(&self, line: &[u8]) -> Option<HtmlBlockEnd> {
+        if line.starts_with(b"<!--") {
+            return Some(CommentEnd);
+        }
+
+        if !line.starts_with(b"<") { return None; }
+        let tag_start = if line.starts_with(b"</") { 2 } else { 1 };
+        let tag_end = line[tag_start..].iter()
+            .position(|&c| !(c as char).is_alphanumeric())
+            .map(|i| i + tag_start).unwrap_or(line.len());
+
+        // tag name should be followed by whitespace, end of line or end of the tag
+        match line.get(tag_end) {
+            None | Some(&b' ') | Some(&b'\n') | Some(&b'>') => {}
+            Some(&b'/') if line.get(tag_end+1) == Some(&b'>') => {}
+            Some(_) => return None
+        }
+
+        let tag = line[tag_start..tag_end].to_ascii_lowercase();
+        if tag_start == 1 {
+            match RAW_TAGS.iter().find(|t| t.as_bytes() == tag.as_slice()) {
+                Some(t) => return Some(ClosingTag(*t)),
+                None => {}
+            }
+        }
+        if BLOCK_TAGS.iter().any(|t| t.as_bytes() == tag.as_slice()) {
+            Some(EmptyLine)
+        } else {
+            None
+        }
+    }
+
+    fn parse_html_block_contents(&self) -> ParseResult<String> {
+        let m = self.cur.mark();
+        let pm = self.cur.phantom_mark();
+
+        parse_or_ret!(self.try_skip_initial_spaces());
+
+        let pm_line = self.cur.phantom_mark();
+        parse_or_ret!(self.read_line());
+        let end = match self.html_block_start(self.cur.slice_to_now_from(pm_line)) {
+            Some(end) => end,
+            None => return NoParse
+        };
+
+        let mut pm_line = pm_line;
+        loop {
+            let line = self.cur.slice_to_now_from(pm_line).to_ascii_lowercase();
+            let finished = match end {
+                CommentEnd => contains(line.as_slice(), b"-->"),
+                ClosingTag(tag) => contains(line.as_slice(), format!("</{}>", tag).as_bytes()),
+                EmptyLine => {
+                    let _m = self.cur.mark();
+                    match self.try_parse_empty_line() {
+                        Success(_) | End => true,
+                        NoParse => false
+                    }
+                }
+            };
+            if finished || !self.cur.available() { break; }
+
+            pm_line = self.cur.phantom_mark();
+            self.read_line();
+        }
+
+        m.cancel();
+
+        Success(to_text(self.cur.slice_to_now_from(pm)))
+    }
+}
+
+impl<'a> HtmlBlockParser for MarkdownParser<'a> {
+    fn parse_html_block(&self) -> ParseResult<Block> {
+        let strip = !self.config.allow_html;
+        if strip && self.config.disallowed_html == DisallowedHtml::Escape { return NoParse; }
+
+        debug!(">> trying html block");
+        loop {
+            let content = parse_or_ret!(self.parse_html_block_contents());
+            let content = if strip {
+                String::new()
+            } else if self.config.strip_html_comments {
+                strip_comments(content.as_slice())
+            } else {
+                return Success(HtmlBlock(content));
+            };
+            if !content.as_slice().trim().is_empty() {
+                return Success(HtmlBlock(content));
+            }
+
+            // the block was stripped or consisted of comments only, so it is
+            // skipped entirely; if there is no other HTML block after it,
+            // the following content is left for other block parsers
+            while ret_on_end!(self.try_parse_empty_line()).is_success() {}
+        }
+    }
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+fn strip_comments(s: &str) -> String {
+    let mut result = String::new();
+    let mut rest = s;
+    loop {
+        match rest.find_str("<!--") {
+            Some(start) => {
+                result.push_str(&rest[..start]);
+                match rest[start..].find_str("-->") {
+                    Some(end) => rest = &rest[start+end+3..],
+                    None => return result  // unterminated comment lasts until the end
+                }
+            }
+            None => {
+                result.push_str(rest);
+                return result;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use parser::{MarkdownParser, MarkdownConfig, DisallowedHtml};
+    use render::render_html;
+    use tokens::*;
+
+    fn parse(s: &str, config: MarkdownConfig) -> Document {
+        MarkdownParser::from_str(s).with_config(config).read_all()
+    }
+
+    static COMMENTED: &'static str = "<!-- note -->\n\n<div>x</div>\n\na <!-- c -->b\n";
+
+    #[test]
+    fn comments_stripped() {
+        let config = MarkdownConfig::default().allow_html(true).strip_html_comments(true);
+        assert_eq!(parse(COMMENTED, config), vec![
+            HtmlBlock("<div>x</div>\n".to_string()),
+            Paragraph(vec![Chunk("a b".to_string())])
+        ]);
+    }
+
+    #[test]
+    fn comments_kept() {
+        assert_eq!(parse(COMMENTED, MarkdownConfig::default().allow_html(true)), vec![
+            HtmlBlock("<!-- note -->\n".to_string()),
+            HtmlBlock("<div>x</div>\n".to_string()),
+            Paragraph(vec![Chunk("a ".to_string()), InlineHtml("<!-- c -->".to_string()),
+                           Chunk("b".to_string())])
+        ]);
+    }
+
+    static SCRIPTS: &'static str = "<script>\nalert(1)\n</script>\n\na <script>x</script> b\n";
+
+    #[test]
+    fn disallowed_html_escaped() {
+        let doc = parse(SCRIPTS, MarkdownConfig::default());
+        assert_eq!(render_html(&doc), "<p>&lt;script&gt;\nalert(1)\n&lt;/script&gt;</p>\n\
+                                       <p>a &lt;script&gt;x&lt;/script&gt; b</p>\n");
+    }
+
+    #[test]
+    fn disallowed_html_stripped() {
+        let doc = parse(SCRIPTS, MarkdownConfig::default().disallowed_html(DisallowedHtml::Strip));
+        assert_eq!(doc, vec![Paragraph(vec![Chunk("a  b".to_string())])]);
+        assert_eq!(render_html(&doc), "<p>a  b</p>\n");
+
+        let doc = parse("a <STYLE>p { x: y }</Style>b <em>c</em>\n",
+                        MarkdownConfig::default().disallowed_html(DisallowedHtml::Strip));
+        assert_eq!(doc, vec![Paragraph(vec![Chunk("a b c".to_string())])]);
+    }
+}
@@ -1,80 +1,439 @@
-use parser::{MarkdownParser, ParseResult, Success, End, NoParse};
+use parser::{MarkdownParser, PhantomMark, ParseResult, Success, End, NoParse, NestingLimitExceeded};
 use tokens::*;
+use util::CharOps;
+
+use parser::block::atx_heading::AtxHeadingParser;
+use parser::block::misc::MiscParser;
 
 use self::ListItemInfo::*;
 
-#[derive(Copy)]
+#[derive(Copy, PartialEq, Eq)]
 enum ListItemInfo {
     Ordered {
-        start: usize
-    }, 
+        start: usize,
+        delim: u8
+    },
     Unordered {
         marker: u8
     },
     Unknown
 }
 
+impl ListItemInfo {
+    // checks whether an item with this marker may continue a list of `current` items
+    fn continues(self, current: ListItemInfo) -> bool {
+        match (current, self) {
+            (Unknown, _) => true,
+            (Ordered { delim: d1, .. }, Ordered { delim: d2, .. }) => d1 == d2,
+            (Unordered { marker: m1 }, Unordered { marker: m2 }) => m1 == m2,
+            _ => false
+        }
+    }
+}
+
+struct ListItem {
+    content: Document,
+    info: ListItemInfo,
+    // blank line between direct children of the item
+    loose: bool
+}
+
 pub trait ListsParser {
     fn parse_list(&self) -> ParseResult<Block>;
+    fn lookahead_paragraph_interrupting_list(&self) -> bool;
 }
 
 impl<'a> ListsParser for MarkdownParser<'a> {
     fn parse_list(&self) -> ParseResult<Block> {
+        debug!(">> trying list");
+        if self.nesting_exceeded() {
+            let start = self.cur.phantom_mark();
+            let _m = self.cur.mark();
+            if self.parse_list_marker().is_success() {
+                self.warn(NestingLimitExceeded, self.source_range(start),
+                          "list nested too deeply".to_string());
+            }
+            return NoParse;
+        }
         let mut result = Vec::new();
         let mut current_item = Unknown;
+        let mut first_item = Unknown;
+        let mut tight = true;
+
         loop {
             let m = self.cur.mark();
+
+            // empty lines are left for the enclosing parser if the list ends after them
+            let mut blank = false;
+            while self.try_parse_empty_line().is_success() { blank = true; }
+
             match self.parse_list_item(current_item) {
-                Success((d, i)) => {
-                    result.push(d);
-                    current_item = i;
+                Success(item) => {
+                    // blank lines between items make the list loose
+                    if (blank && !result.is_empty()) || item.loose { tight = false; }
+
+                    result.push(item.content);
+                    if current_item == Unknown { first_item = item.info; }
+                    current_item = item.info;
                     m.cancel();
                 }
                 NoParse | End => break
             }
         }
 
-        match current_item {
+        match first_item {
             Unknown => NoParse,
-            Ordered { start } => Success(OrderedList {
+            Ordered { start, .. } => Success(OrderedList {
                 start_index: start,
-                items: result
+                items: result,
+                tight: tight
             }),
             Unordered { .. } => Success(UnorderedList {
-                items: result
+                items: result,
+                tight: tight
             })
         }
     }
+
+    // only lists starting with a non-empty item, and with 1 for ordered
+    // lists, may interrupt a paragraph
+    fn lookahead_paragraph_interrupting_list(&self) -> bool {
+        let _m = self.cur.mark();
+        let info = match self.parse_list_marker() {
+            Success((info, _)) => info,
+            _ => return false
+        };
+        let empty = match self.try_parse_empty_line() {
+            NoParse => false,
+            _ => true
+        };
+        match info {
+            Ordered { start, .. } => start == 1 && !empty,
+            _ => !empty
+        }
+    }
 }
 
 trait Ops {
-    fn parse_list_item(&self, current_item: ListItemInfo) 
-        -> ParseResult<(Document, ListItemInfo)>;
-    fn parse_list_item_content(&self) -> ParseResult<Document>;
+    fn parse_list_marker(&self) -> ParseResult<(ListItemInfo, usize)>;
+    fn parse_list_item(&self, current_item: ListItemInfo) -> ParseResult<ListItem>;
+    fn parse_list_item_contents(&self, buf: &[u8], start: PhantomMark) -> (Document, bool);
+    fn lookahead_block_start(&self) -> bool;
+    fn read_indented_line_to(&self, width: usize, buf: &mut Vec<u8>) -> bool;
 }
 
 impl<'a> Ops for MarkdownParser<'a> {
-    fn parse_list_item(&self, current_item: ListItemInfo) 
-            -> ParseResult<(Document, ListItemInfo)> {
-        parse_or_ret!(self.try_skip_initial_spaces());
-
-        //match current_item {
-            //Ordered { .. } => {}
-            //Unordered { marker } => {}
-            //Unknown => {
-                //match self.parse(|c: u8| c.is_numeric()) {
-                    //Success(n) => {
-                        //let start: usize = from_str(n).unwrap();
-                        //self.parse_list_item_content()
-                            //.map(|d| (d, Ordered { start: start }))
-                    //}
-                //}
-            //}
-        //}
-        unimplemented!()
-    }
-
-    fn parse_list_item_content(&self) -> ParseResult<Document> {
-        unimplemented!()
+    // Parses list marker with the following spaces, returns the marker
+    // and the indentation of the item contents.
+    fn parse_list_marker(&self) -> ParseResult<(ListItemInfo, usize)> {
+        let m = self.cur.mark();
+
+        let mut indent = 0;
+        while indent < 4 && self.cur.current_byte() == Some(b' ') {
+            self.cur.next();
+            indent += 1;
+        }
+        if indent == 4 { return NoParse; }
+
+        let info = match opt_ret_end!(self.cur.next_byte()) {
+            c if one_of!(c, b'-', b'+', b'*') => Unordered { marker: c },
+            c if c.is_numeric() => {
+                let mut start = (c - b'0') as usize;
+                let mut digits = 1;
+                let delim;
+                loop {
+                    match self.cur.next_byte() {
+                        Some(c) if c.is_numeric() && digits < 9 => {
+                            start = start * 10 + (c - b'0') as usize;
+                            digits += 1;
+                        }
+                        Some(c) if one_of!(c, b'.', b')') => { delim = c; break }
+                        _ => return NoParse
+                    }
+                }
+                Ordered { start: start, delim: delim }
+            }
+            _ => return NoParse
+        };
+        let marker_width = self.cur.pos.get() - m.pos;
+
+        // count columns of spaces and tabs after the marker; tabs advance to the
+        // next tab stop from the start of the line
+        let pm = self.cur.phantom_mark();
+        let mut column = marker_width;
+        loop {
+            match self.cur.current_byte() {
+                Some(b' ') => column += 1,
+                Some(b'\t') => column += self.config.tab_width - column % self.config.tab_width,
+                _ => break
+            }
+            self.cur.next();
+        }
+        let spaces = column - marker_width;
+
+        let width = match self.cur.current_byte() {
+            // item starting with an empty line
+            Some(b'\n') | None => marker_width + 1,
+            // marker should be followed by a space or a tab
+            Some(_) if spaces == 0 => return NoParse,
+            // indented code inside an item, only one space belongs to the marker
+            Some(_) if spaces > 4 => {
+                self.cur.pos.set(pm.pos + 1);
+                marker_width + 1
+            }
+            Some(_) => marker_width + spaces
+        };
+
+        m.cancel();
+        Success((info, width))
+    }
+
+    fn parse_list_item(&self, current_item: ListItemInfo) -> ParseResult<ListItem> {
+        let start = self.cur.phantom_mark();
+        let m = self.cur.mark();
+        let (info, width) = parse_or_ret!(self.parse_list_marker());
+        if !info.continues(current_item) { return NoParse; }
+
+        let mut buf = Vec::new();
+        parse_or_ret!(self.read_line_to(&mut buf));
+
+        let mut last_blank = false;
+        let mut pm_blank = self.cur.phantom_mark();
+        loop {
+            if !self.cur.available() { break; }
+
+            // empty lines are a part of the item if the item continues after them
+            let pm = self.cur.phantom_mark();
+            match self.try_parse_empty_line() {
+                Success(_) => {
+                    if !last_blank { pm_blank = pm; }
+                    // spaces beyond the item indentation are significant e.g. in code blocks
+                    let line = self.cur.slice_until_now_from(pm);
+                    if line.len() > width {
+                        buf.push_all(&line[width..]);
+                    }
+                    buf.push(b'\n');
+                    last_blank = true;
+                    continue
+                }
+                End => {
+                    if !last_blank { pm_blank = pm; }
+                    last_blank = true;
+                    break
+                }
+                NoParse => {}
+            }
+
+            // indented lines are item contents
+            if self.read_indented_line_to(width, &mut buf) {
+                last_blank = false;
+                continue;
+            }
+
+            // lazy continuation of a paragraph; its indentation is less than
+            // the item requires, so it is not a part of the content
+            if !last_blank && !self.lookahead_block_start() {
+                self.skip_spaces();
+                self.read_line_to(&mut buf);
+                continue;
+            }
+
+            break;
+        }
+
+        // trailing empty lines do not belong to the item
+        if last_blank {
+            self.cur.pos.set(pm_blank.pos);
+            while buf.len() > 1 && buf[buf.len()-1] == b'\n' && buf[buf.len()-2] == b'\n' {
+                buf.pop();
+            }
+        }
+
+        m.cancel();
+
+        let (content, loose) = self.parse_list_item_contents(buf.as_slice(), start);
+
+        Success(ListItem {
+            content: content,
+            info: info,
+            loose: loose
+        })
+    }
+
+    // Parses blocks of an item, the item is loose if there are empty lines
+    // between any of its direct children.
+    fn parse_list_item_contents(&self, buf: &[u8], start: PhantomMark) -> (Document, bool) {
+        let mut subp = self.fork_container(buf, start, self.cur.phantom_mark());
+        let mut result = Vec::new();
+        let mut loose = false;
+        loop {
+            // queued blocks immediately follow the previous one; otherwise
+            // the previous block could have consumed the empty line after it
+            let mut blank = false;
+            if subp.event_queue.borrow().is_empty() {
+                blank = ends_with_empty_line(&subp.cur.buf[..subp.cur.pos.get()]);
+                while subp.try_parse_empty_line().is_success() { blank = true; }
+            }
+            match subp.next() {
+                Some(block) => {
+                    if blank && !result.is_empty() { loose = true; }
+                    result.push(block);
+                }
+                None => break
+            }
+        }
+        (self.fix_links(result), loose)
+    }
+
+    // Reads a line indented by at least `width` columns without the indentation.
+    // Tabs advance to the next tab stop; the indentation is expanded to spaces
+    // so that columns of nested items stay the same.
+    fn read_indented_line_to(&self, width: usize, buf: &mut Vec<u8>) -> bool {
+        let m = self.cur.mark();
+        let mut column = 0;
+        loop {
+            match self.cur.current_byte() {
+                Some(b' ') => column += 1,
+                Some(b'\t') => column += self.config.tab_width - column % self.config.tab_width,
+                _ => break
+            }
+            self.cur.next();
+        }
+        if column < width { return false; }
+
+        m.cancel();
+        for _ in width..column { buf.push(b' '); }
+        self.read_line_to(buf);
+        true
+    }
+
+    // checks whether the current line starts a block which ends a lazy paragraph
+    fn lookahead_block_start(&self) -> bool {
+        let _m = self.cur.mark();
+
+        if self.parse_list_marker().is_success() { return true; }
+        if self.parse_horizontal_rule().is_success() { return true; }
+        if self.parse_atx_heading().is_success() { return true; }
+
+        // block quote or code fence start
+        for _ in 0..3 {
+            if self.cur.current_byte() == Some(b' ') { self.cur.next(); }
+        }
+        match self.cur.current_byte() {
+            Some(b'>') => true,
+            Some(c) if one_of!(c, b'`', b'~') => self.lookahead_chars(3, c),
+            _ => false
+        }
+    }
+}
+
+fn ends_with_empty_line(buf: &[u8]) -> bool {
+    match buf.last() {
+        Some(&b'\n') => buf[..buf.len()-1].iter().rev()
+            .take_while(|&&c| c != b'\n')
+            .all(|&c| one_of!(c, b' ', b'\t')),
+        _ => false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use parser::{MarkdownParser, MarkdownConfig};
+    use render::render_html;
+    use tokens::*;
+
+    fn parse(s: &str, config: MarkdownConfig) -> Document {
+        MarkdownParser::from_str(s).with_config(config).read_all()
+    }
+
+    fn html(s: &str) -> String {
+        render_html(&parse(s, MarkdownConfig::default()))
+    }
+
+    #[test]
+    fn blank_line_before_sublist_makes_list_loose() {
+        assert_eq!(html("- a\n\n  - b\n"),
+                   "<ul>\n<li>\n<p>a</p>\n<ul>\n<li>b</li>\n</ul>\n</li>\n</ul>\n");
+    }
+
+    #[test]
+    fn loose_sublist_in_tight_list() {
+        assert_eq!(html("- a\n  - b\n\n    c\n- d\n"),
+                   "<ul>\n<li>a\n<ul>\n<li>\n<p>b</p>\n<p>c</p>\n</li>\n</ul>\n</li>\n\
+                    <li>d</li>\n</ul>\n");
+    }
+
+    fn paragraph(s: &str) -> Block { Paragraph(vec![Chunk(s.to_string())]) }
+
+    #[test]
+    fn list_interrupting_paragraph() {
+        assert_eq!(parse("a\n2. b\n", MarkdownConfig::default()), vec![paragraph("a\n2. b")]);
+        assert_eq!(parse("a\n-\n", MarkdownConfig::default()), vec![Heading {
+            level: 2,
+            content: vec![Chunk("a".to_string())],
+            attributes: None
+        }]);
+        assert_eq!(parse("a\n1. b\n", MarkdownConfig::default()), vec![paragraph("a"), OrderedList {
+            start_index: 1,
+            items: vec![vec![paragraph("b")]],
+            tight: true
+        }]);
+    }
+
+    #[test]
+    fn lenient_interruption() {
+        let config = MarkdownConfig::default().lenient_interruption(true);
+        assert_eq!(parse("a\n2. b\n", config), vec![paragraph("a"), OrderedList {
+            start_index: 2,
+            items: vec![vec![paragraph("b")]],
+            tight: true
+        }]);
+    }
+
+    #[test]
+    fn indented_code_in_item() {
+        assert_eq!(parse("- a\n\n      code\n", MarkdownConfig::default()), vec![UnorderedList {
+            items: vec![vec![paragraph("a"), BlockCode {
+                tag: None,
+                content: "code\n".to_string(),
+                attributes: None
+            }]],
+            tight: false
+        }]);
+    }
+
+    #[test]
+    fn lazy_continuation_in_item() {
+        assert_eq!(parse("1.  a\n  b\n", MarkdownConfig::default()), vec![OrderedList {
+            start_index: 1,
+            items: vec![vec![paragraph("a\nb")]],
+            tight: true
+        }]);
+        assert_eq!(html("- a\n\n  b\n"), "<ul>\n<li>\n<p>a</p>\n<p>b</p>\n</li>\n</ul>\n");
+        assert_eq!(html("-   a\n\n  b\n"), "<ul>\n<li>a</li>\n</ul>\n<p>b</p>\n");
+    }
+
+    #[test]
+    fn wide_marker_starts_code() {
+        assert_eq!(html("1.     indented code\n\n   paragraph\n\n       more code\n"),
+                   "<ol>\n<li>\n<pre><code>indented code\n</code></pre>\n<p>paragraph</p>\n\
+                    <pre><code>more code\n</code></pre>\n</li>\n</ol>\n");
+        assert_eq!(html("-      code\n"), "<ul>\n<li>\n<pre><code> code\n</code></pre>\n</li>\n</ul>\n");
+    }
+
+    #[test]
+    fn whitespace_lines_are_empty() {
+        use super::ends_with_empty_line;
+        assert!(ends_with_empty_line(b"a\n\n"));
+        assert!(ends_with_empty_line(b"a\n  \n"));
+        assert!(ends_with_empty_line(b"a\n \t\n"));
+        assert!(!ends_with_empty_line(b"a\n"));
+        assert!(!ends_with_empty_line(b"a\n \tb\n"));
+    }
+
+    #[test]
+    fn tabs_after_markers() {
+        assert_eq!(html("-\tfoo\n\tbar\n"), "<ul>\n<li>foo\nbar</li>\n</ul>\n");
+        assert_eq!(html("1.\tfoo\n2.\tbar\n"), "<ol>\n<li>foo</li>\n<li>bar</li>\n</ol>\n");
     }
 }
@@ -2,8 +2,11 @@ use std::num::FromPrimitive;
 
 use parser::{MarkdownParser, ParseResult, Success, End, NoParse};
 use tokens::*;
-use parser::block::atx_heading::AtxHeadingParser;
+use parser::block::atx_heading::{AtxHeadingParser, make_heading};
 use parser::block::block_quote::BlockQuoteParser;
+use parser::block::fenced_code::FencedCodeParser;
+use parser::block::html::HtmlBlockParser;
+use parser::block::attributes::split_attributes;
 use parser::inline::InlineParser;
 
 use self::SetextHeaderLevel::*;
@@ -33,19 +36,23 @@ impl SetextHeaderLevel {
 impl<'a> MiscParser for MarkdownParser<'a> {
     fn parse_horizontal_rule(&self) -> ParseResult<Block> {
         debug!(">> trying hrule");
+        let m = self.cur.mark();
         parse_or_ret!(self.try_skip_initial_spaces());
 
-        let m = self.cur.mark();
         match self.cur.next_byte() {
-            Some(mut c) if one_of!(c, b'-', b'*', b'_')  => {
+            Some(c) if one_of!(c, b'-', b'*', b'_')  => {
+                // markers may be separated by spaces and tabs
+                let mut n = 1;
                 loop {
                     match self.cur.next_byte() {
                         Some(b'\n') | None => break,
-                        Some(b' ') => c = b' ',  // from now on everything should be spaces
-                        Some(cc) if cc == c => {}
+                        Some(b' ') | Some(b'\t') => {}
+                        Some(cc) if cc == c => n += 1,
+                        // any other content makes it a paragraph, e.g. `*** text`
                         Some(_) => return NoParse
                     }
                 }
+                if n < self.config.thematic_break_min { return NoParse; }
                 m.cancel();
                 Success(HorizontalRule)
             }
@@ -55,6 +62,10 @@ impl<'a> MiscParser for MarkdownParser<'a> {
     }
 
     fn parse_paragraph(&self) -> ParseResult<Block> {
+        // imported here to avoid cyclic imports with the lists and table modules
+        use parser::block::lists::ListsParser;
+        use parser::block::table::TableParser;
+
         debug!(">> reading paragraph");
 
         let pm = self.cur.phantom_mark();
@@ -72,6 +83,17 @@ impl<'a> MiscParser for MarkdownParser<'a> {
                 NoParse => {}
             }
 
+            // table header with delimiter row takes precedence over setext heading
+            debug!(">> trying to parse table");
+            match self.parse_table() {
+                Success(table) => {
+                    self.enqueue_event(table);
+                    break
+                }
+                End => break,   // End is impossible here
+                NoParse => {}
+            }
+
             // header line means that the paragraph ended, and its last line
             // should be parsed as a heading
             debug!(">> trying to parse header line");
@@ -103,6 +125,30 @@ impl<'a> MiscParser for MarkdownParser<'a> {
                 NoParse => {}
             }
 
+            // Check for fenced code block just after the paragraph
+            debug!(">> trying to parse fenced code block");
+            match self.parse_fenced_code() {
+                Success(code) => {
+                    self.enqueue_event(code);
+                    break
+                }
+                End => break,   // End is impossible here
+                NoParse => {}
+            }
+
+            // Check for HTML block just after the paragraph
+            debug!(">> trying to parse HTML block");
+            let pm_before = self.cur.phantom_mark();
+            match self.parse_html_block() {
+                Success(html) => {
+                    self.enqueue_event(html);
+                    break
+                }
+                End => break,
+                // stripped HTML comments still end the paragraph
+                NoParse => if self.cur.phantom_mark() != pm_before { break }
+            }
+
             // Check for block quote just after the paragraph
             debug!(">> trying to parse block quote");
             match self.parse_block_quote() {
@@ -114,7 +160,19 @@ impl<'a> MiscParser for MarkdownParser<'a> {
                 NoParse => {}
             }
 
-            // TODO: lax spacing rules: check for list/html, block/code fence or quote
+            // Check for list just after the paragraph, unless the list is
+            // not allowed to interrupt it
+            debug!(">> trying to parse list");
+            if self.config.lenient_interruption || self.lookahead_paragraph_interrupting_list() {
+                match self.parse_list() {
+                    Success(list) => {
+                        self.enqueue_event(list);
+                        break
+                    }
+                    End => break,   // End is impossible here
+                    NoParse => {}
+                }
+            }
         }
 
         let mut buf = self.cur.slice(pm, pm_last);
@@ -130,15 +188,16 @@ impl<'a> MiscParser for MarkdownParser<'a> {
 
                 // last newline or start of the block
                 let after_nl_idx = sbuf.rposition_elem(&b'\n').map(|i| i + 1).unwrap_or(0);
-                let head_content = &sbuf[after_nl_idx..];
+                let (head_content, attributes) = if self.config.attributes {
+                    split_attributes(&sbuf[after_nl_idx..])
+                } else {
+                    (&sbuf[after_nl_idx..], None)
+                };
 
-                let subp = self.fork(head_content);
-                let result = self.fix_links(subp.parse_inline());
+                let result = self.parse_heading_inline(head_content, pm, pm_last);
 
-                let heading_result = Heading {
-                    level: level.to_numeric(),
-                    content: result
-                };
+                let heading_result = make_heading(&*self.config, level.to_numeric(),
+                                                  result, attributes);
 
                 buf = &buf[..after_nl_idx];
 
@@ -151,7 +210,7 @@ impl<'a> MiscParser for MarkdownParser<'a> {
             None => {}
         }
 
-        let subp = self.fork(buf);
+        let subp = self.fork_container(buf, pm, pm_last);
         let result = self.fix_links(subp.parse_inline());
 
         Success(Paragraph(result))
@@ -166,6 +225,8 @@ impl<'a> Ops for MarkdownParser<'a> {
     fn try_parse_header_line(&self) -> ParseResult<SetextHeaderLevel> {
         let m = self.cur.mark();
 
+        // up to three spaces of indentation are allowed
+        parse_or_ret!(self.try_skip_initial_spaces());
         let mut cc = match self.cur.next_byte() {
             Some(c) if one_of!(c, b'=', b'-') => c,
             Some(_) => return NoParse,
@@ -177,7 +238,8 @@ impl<'a> Ops for MarkdownParser<'a> {
             match self.cur.next_byte() {
                 None | Some(b'\n') => break,
                 Some(c) if c == cc => {},
-                Some(b' ') => cc = b' ',  // consume only spaces from now on
+                // consume only trailing whitespace from now on
+                Some(b' ') | Some(b'\t') => cc = b' ',
                 Some(_) => return NoParse
             }
         }
@@ -185,3 +247,58 @@ impl<'a> Ops for MarkdownParser<'a> {
         Success(level)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use parser::{MarkdownParser, MarkdownConfig};
+    use tokens::*;
+
+    fn parse(s: &str, config: MarkdownConfig) -> Document {
+        MarkdownParser::from_str(s).with_config(config).read_all()
+    }
+
+    fn paragraph(s: &str) -> Block { Paragraph(vec![Chunk(s.to_string())]) }
+
+    #[test]
+    fn thematic_break_min() {
+        let config = MarkdownConfig::default().thematic_break_min(5);
+        assert_eq!(parse("---\n", config.clone()), vec![paragraph("---")]);
+        assert_eq!(parse("-----\n", config), vec![HorizontalRule]);
+    }
+
+    #[test]
+    fn spaced_thematic_break() {
+        assert_eq!(parse(" - - -\n", MarkdownConfig::default()), vec![HorizontalRule]);
+    }
+
+    #[test]
+    fn tabs_in_thematic_break() {
+        assert_eq!(parse("-\t-\t-\n", MarkdownConfig::default()), vec![HorizontalRule]);
+        assert_eq!(parse("*\t *\t*\t\n", MarkdownConfig::default()), vec![HorizontalRule]);
+    }
+
+    #[test]
+    fn thematic_break_with_text_is_paragraph() {
+        assert_eq!(parse("*** text\n", MarkdownConfig::default()), vec![paragraph("*** text")]);
+        assert_eq!(parse("- - - a\n", MarkdownConfig::default()), vec![UnorderedList {
+            items: vec![vec![UnorderedList {
+                items: vec![vec![UnorderedList {
+                    items: vec![vec![paragraph("a")]],
+                    tight: true
+                }]],
+                tight: true
+            }]],
+            tight: true
+        }]);
+    }
+
+    #[test]
+    fn setext_underline_whitespace() {
+        let heading = |level| Heading { level: level, content: vec![Chunk("a".to_string())], attributes: None };
+        assert_eq!(parse("a\n  ===  \n", MarkdownConfig::default()), vec![heading(1)]);
+        assert_eq!(parse("a\n---\t\n", MarkdownConfig::default()), vec![heading(2)]);
+        assert_eq!(parse("a\n=== x\n", MarkdownConfig::default()), vec![paragraph("a\n=== x")]);
+        assert_eq!(parse("a\n= =\n", MarkdownConfig::default()), vec![paragraph("a\n= =")]);
+        assert_eq!(parse("a\n    ===\n", MarkdownConfig::default()), vec![paragraph("a\n    ===")]);
+    }
+}
@@ -1,17 +1,32 @@
-use parser::{MarkdownParser, ParseResult, End};
+use parser::{MarkdownParser, ParseResult, Success, End};
 use tokens::*;
 
 use self::block_quote::BlockQuoteParser;
 use self::block_code::BlockCodeParser;
+use self::fenced_code::FencedCodeParser;
 use self::atx_heading::AtxHeadingParser;
 use self::lists::ListsParser;
 use self::misc::MiscParser;
+use self::html::HtmlBlockParser;
+use self::table::TableParser;
+use self::reference::ReferenceParser;
+use self::front_matter::FrontMatterParser;
+use self::footnote::FootnoteParser;
+use self::definition_list::DefinitionListParser;
 
 mod block_quote;
 mod block_code;
+mod fenced_code;
 mod atx_heading;
 mod lists;
 mod misc;
+mod html;
+mod attributes;
+mod table;
+mod reference;
+mod front_matter;
+mod footnote;
+mod definition_list;
 
 pub trait BlockParser {
     fn parse_block(&self) -> ParseResult<Block>;
@@ -20,15 +35,26 @@ pub trait BlockParser {
 impl<'a> BlockParser for MarkdownParser<'a> {
     fn parse_block(&self) -> ParseResult<Block> {
         debug!("--- parsing a block");
+        match self.parse_front_matter() {
+            Success(front_matter) => return Success(front_matter),
+            _ => {}
+        }
+
         // Skip empty lines
         while ret_on_end!(self.try_parse_empty_line()).is_success() {}
 
         first_of! {
+            self.parse_reference(),
+            self.parse_footnote_definition(),
+            self.parse_html_block(),
             self.parse_block_quote(),
             self.parse_block_code(),
+            self.parse_fenced_code(),
             self.parse_horizontal_rule(),
             self.parse_atx_heading(),
             self.parse_list(),
+            self.parse_table(),
+            self.parse_definition_list(),
             self.parse_paragraph(),
             panic!("programming error, parsing block failed")
         }
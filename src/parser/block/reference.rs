@@ -0,0 +1,237 @@
+use parser::{MarkdownParser, ParseResult, Success, End, NoParse};
+use tokens::*;
+use util::{to_text, unescape};
+
+pub trait ReferenceParser {
+    // definitions do not produce blocks, so they are consumed and NoParse
+    // is returned to let other parsers continue after them
+    fn parse_reference(&self) -> ParseResult<Block>;
+}
+
+trait Ops {
+    // the description is None if the destination scheme is not allowed
+    fn parse_reference_definition(&self) -> Option<(String, Option<LinkDescription>)>;
+    fn parse_reference_destination(&self) -> Option<String>;
+    fn parse_reference_title(&self) -> Option<String>;
+    fn skip_line_end(&self) -> bool;
+}
+
+impl<'a> Ops for MarkdownParser<'a> {
+    fn parse_reference_definition(&self) -> Option<(String, Option<LinkDescription>)> {
+        let m = self.cur.mark();
+        parse_or_ret_none!(self.try_skip_initial_spaces());
+        parse_or_ret_none!(self.try_read_char(b'['));
+
+        // label may not contain unescaped brackets
+        let pm = self.cur.phantom_mark();
+        loop {
+            match opt_ret!(self.cur.next_byte()) {
+                b'\\' => { self.cur.next(); }
+                b'[' => return None,
+                b']' => break,
+                _ => {}
+            }
+        }
+        let label = self.cur.slice_until_now_from(pm);
+        let key = opt_ret!(normalize_link_label(label));
+        // `[^label]:` defines a footnote instead
+        if self.config.footnotes && label.first() == Some(&b'^') { return None; }
+        parse_or_ret_none!(self.try_read_char(b':'));
+
+        // destination may be on the next line
+        self.skip_spaces();
+        if self.cur.current_byte() == Some(b'\n') {
+            self.cur.next();
+            self.skip_spaces();
+        }
+        let link = opt_ret!(self.parse_reference_destination());
+
+        // title has to be separated from the destination and may be on the next line
+        let title = match self.cur.current_byte() {
+            Some(b' ') | Some(b'\n') => {
+                let m = self.cur.mark();
+                self.skip_spaces();
+                if self.cur.current_byte() == Some(b'\n') {
+                    self.cur.next();
+                    self.skip_spaces();
+                }
+                match self.parse_reference_title() {
+                    Some(ref title) if self.skip_line_end() => cancel!(m; Some(title.clone())),
+                    _ => None
+                }
+            }
+            _ => None
+        };
+        if title.is_none() && !self.skip_line_end() { return None; }
+
+        m.cancel();
+        let label = to_text(label);
+        let link = self.process_url(unescape(link.as_slice()));
+        let title = title.map(|title| unescape(title.as_slice()));
+
+        Some((key, link.map(|link| LinkDescription {
+            id: label,
+            link: link,
+            title: title
+        })))
+    }
+
+    fn parse_reference_destination(&self) -> Option<String> {
+        if self.cur.current_byte() == Some(b'<') {
+            self.cur.next();
+            let pm = self.cur.phantom_mark();
+            loop {
+                match opt_ret!(self.cur.next_byte()) {
+                    b'\\' => { self.cur.next(); }
+                    b'>' => break,
+                    b'<' | b'\n' => return None,
+                    _ => {}
+                }
+            }
+            return Some(to_text(self.cur.slice_until_now_from(pm)));
+        }
+
+        // parentheses should be balanced
+        let pm = self.cur.phantom_mark();
+        let mut level = 0usize;
+        loop {
+            match self.cur.current_byte() {
+                None | Some(b' ') | Some(b'\n') => break,
+                Some(b'\\') => { self.cur.next(); }
+                Some(b'(') => level += 1,
+                Some(b')') => if level == 0 { return None; } else { level -= 1; },
+                Some(_) => {}
+            }
+            self.cur.next();
+        }
+
+        let link = self.cur.slice_to_now_from(pm);
+        if level > 0 || link.is_empty() { return None; }
+        Some(to_text(link))
+    }
+
+    fn parse_reference_title(&self) -> Option<String> {
+        let close = match opt_ret!(self.cur.next_byte()) {
+            b'"' => b'"',
+            b'\'' => b'\'',
+            b'(' => b')',
+            _ => return None
+        };
+
+        let pm = self.cur.phantom_mark();
+        loop {
+            match opt_ret!(self.cur.next_byte()) {
+                b'\\' => { self.cur.next(); }
+                c if c == close => break,
+                b'(' if close == b')' => return None,
+                // title may not contain empty lines
+                b'\n' => if self.try_parse_empty_line().is_success() { return None; },
+                _ => {}
+            }
+        }
+        Some(to_text(self.cur.slice_until_now_from(pm)))
+    }
+
+    // skips trailing spaces, fails if there is something else on the line
+    fn skip_line_end(&self) -> bool {
+        self.skip_spaces();
+        match self.cur.next_byte() {
+            Some(b'\n') | None => true,
+            Some(_) => false
+        }
+    }
+}
+
+impl<'a> ReferenceParser for MarkdownParser<'a> {
+    fn parse_reference(&self) -> ParseResult<Block> {
+        debug!(">> trying reference definition");
+        loop {
+            let start = self.cur.phantom_mark();
+            let (label, ld) = match self.parse_reference_definition() {
+                Some(r) => r,
+                None => return NoParse
+            };
+            debug!(">> read reference definition: {}", label);
+            // such definitions are consumed, but references to them stay undefined
+            match ld {
+                Some(ld) => self.define_reference(label, ld, start),
+                None => {}
+            }
+
+            while ret_on_end!(self.try_parse_empty_line()).is_success() {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use parser::{MarkdownParser, MarkdownConfig, ParseWarning, DuplicateDefinition,
+                 TooManyDefinitions};
+    use render::render_html;
+    use tokens::*;
+
+    fn parse(s: &str, config: MarkdownConfig) -> (Document, Vec<ParseWarning>) {
+        MarkdownParser::from_str(s).with_config(config).read_all_with_warnings()
+    }
+
+    fn link(text: &str, link: &str) -> Inline {
+        Link {
+            text: Some(vec![Chunk(text.to_string())]),
+            link: Some(link.to_string()),
+            title: None,
+            id: Some(text.to_string()),
+            reference: Some(ShortcutReference)
+        }
+    }
+
+    #[test]
+    fn duplicate_definition() {
+        let (doc, warnings) = parse("[a]: /x\n[a]: /y\n\n[a]\n", MarkdownConfig::default());
+        assert_eq!(doc, vec![Paragraph(vec![link("a", "/x")])]);
+        assert_eq!(warnings, vec![ParseWarning {
+            kind: DuplicateDefinition,
+            start: 8,
+            end: 16,
+            message: "duplicate definition of reference `a`".to_string()
+        }]);
+    }
+
+    #[test]
+    fn definitions_are_capped() {
+        let mut s = String::new();
+        for i in 0..1000 {
+            s.push_str(format!("[d{}]: /{}\n", i, i).as_slice());
+        }
+        s.push_str("\n[d0] [d1] [d2] [d3] [d999]\n");
+        let (doc, warnings) = parse(s.as_slice(), MarkdownConfig::default().max_reference_definitions(3));
+        assert_eq!(doc.unresolved_references(), vec!["d3".to_string(), "d999".to_string()]);
+        let exceeded = warnings.iter().filter(|w| w.kind == TooManyDefinitions).collect::<Vec<_>>();
+        assert_eq!(exceeded.len(), 1);
+        assert_eq!(exceeded[0].message, "too many reference definitions, `d3` and later ones are ignored");
+    }
+
+    #[test]
+    fn definitions_only_at_block_start() {
+        let (doc, _) = parse("text\n[a]: /url\n", MarkdownConfig::default());
+        assert_eq!(doc, vec![Paragraph(vec![
+            Chunk("text\n".to_string()),
+            Link { text: Some(vec![Chunk("a".to_string())]), link: None, title: None,
+                   id: Some("a".to_string()), reference: Some(ShortcutReference) },
+            Chunk(": /url".to_string())
+        ])]);
+        assert_eq!(render_html(&doc), "<p>text\n[a]: /url</p>\n");
+
+        let (doc, _) = parse("[a]: /url\ntext [a]\n", MarkdownConfig::default());
+        assert_eq!(doc, vec![Paragraph(vec![Chunk("text ".to_string()), link("a", "/url")])]);
+    }
+
+    #[test]
+    fn labels_match_across_whitespace() {
+        let (doc, warnings) = parse("[Foo\tbar\nbaz] and [a\u{a0}b]\n\n[foo bar baz]: /x\n[A B]: /y\n",
+                                    MarkdownConfig::default());
+        assert!(warnings.is_empty());
+        assert!(doc.unresolved_references().is_empty());
+        assert_eq!(doc.links().iter().map(|l| l.link).collect::<Vec<_>>(),
+                   vec![Some("/x"), Some("/y")]);
+    }
+}
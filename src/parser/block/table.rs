@@ -0,0 +1,333 @@
+use parser::{MarkdownParser, PhantomMark, ParseResult, Success, End, NoParse};
+use parser::block::atx_heading::AtxHeadingParser;
+use parser::block::misc::MiscParser;
+use parser::inline::InlineParser;
+use tokens::*;
+use util::ByteSliceOps;
+
+pub trait TableParser {
+    fn parse_table(&self) -> ParseResult<Block>;
+}
+
+trait Ops {
+    fn parse_table_row(&self, line: &[u8], start: PhantomMark) -> Vec<Text>;
+    fn lookahead_table_end(&self) -> bool;
+}
+
+impl<'a> Ops for MarkdownParser<'a> {
+    fn parse_table_row(&self, line: &[u8], start: PhantomMark) -> Vec<Text> {
+        let end = PhantomMark { pos: start.pos + line.len() };
+        split_cells(line).into_iter()
+            .map(|cell| {
+                let cell = unescape_pipes(cell);
+                self.fix_links(self.fork_container(cell.as_slice(), start, end).parse_inline())
+            })
+            .collect()
+    }
+
+    // checks whether the current line ends the table body
+    fn lookahead_table_end(&self) -> bool {
+        let _m = self.cur.mark();
+
+        match self.try_parse_empty_line() {
+            NoParse => {}
+            _ => return true
+        }
+        if self.parse_horizontal_rule().is_success() { return true; }
+        if self.parse_atx_heading().is_success() { return true; }
+
+        // block quote or code fence start
+        for _ in 0..3 {
+            if self.cur.current_byte() == Some(b' ') { self.cur.next(); }
+        }
+        match self.cur.current_byte() {
+            Some(b'>') => true,
+            Some(c) if one_of!(c, b'`', b'~') => self.lookahead_chars(3, c),
+            _ => false
+        }
+    }
+}
+
+impl<'a> TableParser for MarkdownParser<'a> {
+    fn parse_table(&self) -> ParseResult<Block> {
+        if !self.config.tables { return NoParse; }
+
+        debug!(">> trying table");
+        let m = self.cur.mark();
+
+        // header row
+        parse_or_ret!(self.try_skip_initial_spaces());
+        let pm_header = self.cur.phantom_mark();
+        parse_or_ret!(self.read_line());
+        let header_line = self.cur.slice_to_now_from(pm_header);
+
+        // delimiter row, it should contain a pipe to be distinguishable
+        // from setext heading underline
+        match self.try_skip_initial_spaces() {
+            Success(_) => {}
+            _ => return NoParse
+        }
+        let pm = self.cur.phantom_mark();
+        match self.read_line() {
+            Success(_) => {}
+            _ => return NoParse
+        }
+        let delimiter_line = self.cur.slice_to_now_from(pm);
+        if !delimiter_line.contains(&b'|') { return NoParse; }
+
+        let mut alignments = Vec::new();
+        for cell in split_cells(delimiter_line).into_iter() {
+            match parse_alignment(cell) {
+                Some(alignment) => alignments.push(alignment),
+                None => return NoParse
+            }
+        }
+
+        let header = self.parse_table_row(header_line, pm_header);
+        if header.len() != alignments.len() { return NoParse; }
+
+        // body rows continue until an empty line or another block; every row is
+        // a single line by design, as in GFM, so a backslash before the newline is
+        // literal text and breaks inside cells can only be written as `<br>`
+        let mut rows = Vec::new();
+        while self.cur.available() && !self.lookahead_table_end() {
+            let m = self.cur.mark();
+            let pm = self.cur.phantom_mark();
+            self.read_line();
+            let mut row = self.parse_table_row(self.cur.slice_to_now_from(pm), pm);
+
+            // in strict mode a malformed row ends the table and is left for other parsers;
+            // otherwise short rows are padded with empty cells and extra cells are dropped
+            if row.len() != header.len() {
+                if self.config.strict_tables { break; }
+                row.truncate(header.len());
+                while row.len() < header.len() { row.push(Vec::new()); }
+            }
+
+            m.cancel();
+            rows.push(row);
+        }
+
+        m.cancel();
+
+        Success(Table {
+            alignments: alignments,
+            header: header,
+            rows: rows
+        })
+    }
+}
+
+// splits a row into cells on unescaped pipes outside of code spans,
+// leading and trailing pipes are optional
+fn split_cells(line: &[u8]) -> Vec<&[u8]> {
+    let line = line.trim_right(|c: u8| c == b'\n' || c == b' ').trim_left(b' ');
+    let line = line.trim_left_one(b'|');
+
+    let mut cells = Vec::new();
+    let mut start = 0;
+    let mut i = 0;
+    while i < line.len() {
+        match line[i] {
+            b'\\' => i += 1,
+            b'`' => i = skip_code_span(line, i) - 1,
+            b'|' => {
+                cells.push(trim_cell(&line[start..i]));
+                start = i + 1;
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    // trailing pipe does not start a new cell
+    if start < line.len() || cells.is_empty() {
+        cells.push(trim_cell(&line[start..]));
+    }
+    cells
+}
+
+// `\|` is a literal pipe in cells, even inside code spans where
+// backslash escapes do not work otherwise
+fn unescape_pipes(cell: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(cell.len());
+    let mut i = 0;
+    while i < cell.len() {
+        match cell[i] {
+            b'\\' if i + 1 < cell.len() => {
+                if cell[i+1] != b'|' { result.push(b'\\'); }
+                result.push(cell[i+1]);
+                i += 2;
+            }
+            c => {
+                result.push(c);
+                i += 1;
+            }
+        }
+    }
+    result
+}
+
+// returns the position after a code span starting at `start`, or after
+// the opening backticks if the span is not closed
+fn skip_code_span(line: &[u8], start: usize) -> usize {
+    let run_end = |i: usize| {
+        let mut j = i;
+        while j < line.len() && line[j] == b'`' { j += 1; }
+        j
+    };
+
+    let open_end = run_end(start);
+    let n = open_end - start;
+    let mut i = open_end;
+    while i < line.len() {
+        if line[i] == b'`' {
+            let j = run_end(i);
+            if j - i == n { return j; }
+            i = j;
+        } else {
+            i += 1;
+        }
+    }
+    open_end
+}
+
+#[inline]
+fn trim_cell(cell: &[u8]) -> &[u8] {
+    cell.trim_left(b' ').trim_right(b' ')
+}
+
+fn parse_alignment(cell: &[u8]) -> Option<Alignment> {
+    let left = cell.starts_with(b":");
+    let right = cell.len() > 1 && cell.ends_with(b":");
+    let dashes = cell.trim_left_one(b':').trim_right_one(b':');
+
+    if dashes.is_empty() || !dashes.iter().all(|&c| c == b'-') {
+        return None;
+    }
+
+    Some(match (left, right) {
+        (true, true) => AlignCenter,
+        (true, false) => AlignLeft,
+        (false, true) => AlignRight,
+        (false, false) => AlignNone
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use parser::{MarkdownParser, MarkdownConfig};
+    use render::render_html;
+    use tokens::*;
+
+    fn parse(s: &str, config: MarkdownConfig) -> Document {
+        MarkdownParser::from_str(s).with_config(config.tables(true)).read_all()
+    }
+
+    fn cell(s: &str) -> Text {
+        if s.is_empty() { Vec::new() } else { vec![Chunk(s.to_string())] }
+    }
+
+    fn row(cells: &[&str]) -> Vec<Text> {
+        cells.iter().map(|s| cell(*s)).collect()
+    }
+
+    #[test]
+    fn table_takes_precedence_over_setext_heading() {
+        assert_eq!(parse("a | b\n--- | ---\n", MarkdownConfig::default()), vec![Table {
+            alignments: vec![AlignNone, AlignNone],
+            header: row(&["a", "b"]),
+            rows: Vec::new()
+        }]);
+    }
+
+    #[test]
+    fn setext_heading_without_pipes() {
+        assert_eq!(parse("a\n---\n", MarkdownConfig::default()), vec![Heading {
+            level: 2,
+            content: cell("a"),
+            attributes: None
+        }]);
+    }
+
+    #[test]
+    fn pipe_in_code_span() {
+        assert_eq!(parse("x | y\n- | -\n`a|b` | *c*\n", MarkdownConfig::default()), vec![Table {
+            alignments: vec![AlignNone, AlignNone],
+            header: row(&["x", "y"]),
+            rows: vec![vec![vec![Code("a|b".to_string())], vec![Emphasis(cell("c"))]]]
+        }]);
+    }
+
+    #[test]
+    fn short_rows_are_padded() {
+        assert_eq!(parse("a | b\n- | -\n1\n", MarkdownConfig::default()), vec![Table {
+            alignments: vec![AlignNone, AlignNone],
+            header: row(&["a", "b"]),
+            rows: vec![row(&["1", ""])]
+        }]);
+    }
+
+    #[test]
+    fn long_rows_are_truncated() {
+        assert_eq!(parse("a | b\n- | -\n1 | 2 | 3\n", MarkdownConfig::default()), vec![Table {
+            alignments: vec![AlignNone, AlignNone],
+            header: row(&["a", "b"]),
+            rows: vec![row(&["1", "2"])]
+        }]);
+    }
+
+    #[test]
+    fn strict_tables_reject_mismatched_rows() {
+        let config = MarkdownConfig::default().strict_tables(true);
+        assert_eq!(parse("a | b\n- | -\n1 | 2\n1 | 2 | 3\n", config), vec![
+            Table {
+                alignments: vec![AlignNone, AlignNone],
+                header: row(&["a", "b"]),
+                rows: vec![row(&["1", "2"])]
+            },
+            Paragraph(cell("1 | 2 | 3"))
+        ]);
+    }
+
+    #[test]
+    fn escaped_pipes() {
+        assert_eq!(parse("x | y\n- | -\na\\|b | `c\\|d`\n", MarkdownConfig::default()), vec![Table {
+            alignments: vec![AlignNone, AlignNone],
+            header: row(&["x", "y"]),
+            rows: vec![vec![cell("a|b"), vec![Code("c|d".to_string())]]]
+        }]);
+        assert_eq!(parse("a\\|b\n", MarkdownConfig::default()), vec![Paragraph(cell("a|b"))]);
+    }
+
+    #[test]
+    fn trailing_backslash_in_cell() {
+        let doc = parse("x | y\n- | -\na\\ | b\\\n\nc\n", MarkdownConfig::default());
+        assert_eq!(render_html(&doc),
+                   "<table>\n<thead>\n<tr>\n<th>x</th>\n<th>y</th>\n</tr>\n</thead>\n\
+                    <tbody>\n<tr>\n<td>a\\</td>\n<td>b\\</td>\n</tr>\n</tbody>\n</table>\n\
+                    <p>c</p>\n");
+    }
+
+    #[test]
+    fn breaks_in_cells() {
+        let doc = parse("x | y\n- | -\na\\b | c\\*d*\n", MarkdownConfig::default());
+        assert_eq!(doc, vec![Table {
+            alignments: vec![AlignNone, AlignNone],
+            header: row(&["x", "y"]),
+            rows: vec![row(&["a\\b", "c*d*"])]
+        }]);
+
+        let doc = parse("x | y\n- | -\na<br>b | c\n", MarkdownConfig::default().allow_html(true));
+        assert_eq!(doc, vec![Table {
+            alignments: vec![AlignNone, AlignNone],
+            header: row(&["x", "y"]),
+            rows: vec![vec![
+                vec![Chunk("a".to_string()), InlineHtml("<br>".to_string()), Chunk("b".to_string())],
+                cell("c")
+            ]]
+        }]);
+        assert_eq!(render_html(&doc),
+                   "<table>\n<thead>\n<tr>\n<th>x</th>\n<th>y</th>\n</tr>\n</thead>\n\
+                    <tbody>\n<tr>\n<td>a<br>b</td>\n<td>c</td>\n</tr>\n</tbody>\n</table>\n");
+    }
+}
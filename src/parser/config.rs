@@ -1,28 +1,231 @@
-#[derive(Copy)]
+use std::fmt;
+use alloc::rc::Rc;
+
+use tokens::FrontMatterFormat;
+
+use self::ConfigError::*;
+use self::DisallowedHtml::*;
+
+// rewrites link and image destinations
+pub type UrlRewriter = Rc<Box<Fn(&str) -> String>>;
+
+#[derive(Clone)]
 pub struct MarkdownConfig {
-    pub trim_newlines: bool
+    pub trim_newlines: bool,
+    pub skip_inline_parsing: bool,
+    pub highlight: bool,
+    pub attributes: bool,
+    pub allow_html: bool,
+    pub strip_html_comments: bool,
+    // what happens to HTML when it is not allowed
+    pub disallowed_html: DisallowedHtml,
+    pub heading_offset: usize,
+    pub heading_ids: bool,
+    pub transliterate_heading_ids: bool,
+    pub breaks: bool,
+    pub tables: bool,
+    pub image_dimensions: bool,
+    pub footnotes: bool,
+    pub definition_lists: bool,
+    pub strict_tables: bool,
+    pub strict_blockquotes: bool,
+    pub merge_adjacent_blockquotes: bool,
+    pub lenient_interruption: bool,
+    pub lenient_atx: bool,
+    pub thematic_break_min: usize,
+    pub tab_width: usize,
+    pub max_nesting_depth: usize,
+    pub max_reference_definitions: usize,
+    pub max_emphasis_delimiters: usize,
+    pub front_matter: Option<FrontMatterFormat>,
+    pub url_rewriter: Option<UrlRewriter>,
+    // links with other schemes are left as text, relative ones are always allowed
+    pub allowed_url_schemes: Option<Vec<String>>
 }
 
 impl MarkdownConfig {
     #[inline]
     pub fn default() -> MarkdownConfig {
         MarkdownConfig {
-            trim_newlines: true
+            trim_newlines: true,
+            skip_inline_parsing: false,
+            highlight: false,
+            attributes: false,
+            allow_html: false,
+            strip_html_comments: false,
+            disallowed_html: Escape,
+            heading_offset: 0,
+            heading_ids: false,
+            transliterate_heading_ids: false,
+            breaks: false,
+            tables: false,
+            image_dimensions: false,
+            footnotes: false,
+            definition_lists: false,
+            strict_tables: false,
+            strict_blockquotes: false,
+            merge_adjacent_blockquotes: false,
+            lenient_interruption: false,
+            lenient_atx: false,
+            thematic_break_min: 3,
+            tab_width: 4,
+            max_nesting_depth: 128,
+            max_reference_definitions: 100000,
+            max_emphasis_delimiters: 1000,
+            front_matter: None,
+            url_rewriter: None,
+            allowed_url_schemes: None
         }
     }
+
+    #[inline]
+    pub fn builder() -> MarkdownConfigBuilder {
+        MarkdownConfigBuilder { config: MarkdownConfig::default() }
+    }
+
+    pub fn rewrite_urls<F>(mut self, f: F) -> MarkdownConfig where F: Fn(&str) -> String + 'static {
+        self.url_rewriter = Some(Rc::new(Box::new(f) as Box<Fn(&str) -> String>));
+        self
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.tab_width == 0 { return Err(ZeroTabWidth); }
+        if self.max_nesting_depth == 0 { return Err(ZeroNestingDepth); }
+        // shorter rules would clash with list markers and setext underlines
+        if self.thematic_break_min < 3 { return Err(ShortThematicBreakMin); }
+        if self.strip_html_comments && !self.allow_html { return Err(StripCommentsWithoutHtml); }
+        Ok(())
+    }
+}
+
+impl_setters! { MarkdownConfig;
+    trim_newlines: bool,
+    skip_inline_parsing: bool,
+    highlight: bool,
+    attributes: bool,
+    allow_html: bool,
+    strip_html_comments: bool,
+    disallowed_html: DisallowedHtml,
+    heading_offset: usize,
+    heading_ids: bool,
+    transliterate_heading_ids: bool,
+    breaks: bool,
+    tables: bool,
+    image_dimensions: bool,
+    footnotes: bool,
+    definition_lists: bool,
+    strict_tables: bool,
+    strict_blockquotes: bool,
+    merge_adjacent_blockquotes: bool,
+    lenient_interruption: bool,
+    lenient_atx: bool,
+    thematic_break_min: usize,
+    tab_width: usize,
+    max_nesting_depth: usize,
+    max_reference_definitions: usize,
+    max_emphasis_delimiters: usize,
+    front_matter: Option<FrontMatterFormat>,
+    url_rewriter: Option<UrlRewriter>,
+    allowed_url_schemes: Option<Vec<String>>
+}
+
+#[derive(Copy, PartialEq, Eq, Debug, Clone)]
+pub enum DisallowedHtml {
+    Escape,  // shown as text
+    Strip    // tags and HTML blocks are removed
+}
+
+#[derive(Copy, PartialEq, Eq, Debug)]
+pub enum ConfigError {
+    ZeroTabWidth,
+    ZeroNestingDepth,
+    ShortThematicBreakMin,
+    StripCommentsWithoutHtml
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            ZeroTabWidth => "tab width should be positive",
+            ZeroNestingDepth => "maximum nesting depth should be positive",
+            ShortThematicBreakMin => "minimum thematic break length should be at least 3",
+            StripCommentsWithoutHtml => "stripping HTML comments requires HTML to be allowed"
+        })
+    }
+}
+
+pub struct MarkdownConfigBuilder {
+    config: MarkdownConfig
 }
 
-macro_rules! impl_setters {
-    ($target:ident; $($name:ident : $t:ty),+) => ($(
-        impl $target {
-            pub fn $name(mut self, value: $t) -> $target {
-                self.$name = value;
-                self
-            }
+impl MarkdownConfigBuilder {
+    #[inline]
+    pub fn build(self) -> Result<MarkdownConfig, ConfigError> {
+        match self.config.validate() {
+            Ok(()) => Ok(self.config),
+            Err(e) => Err(e)
         }
-    )+)
+    }
 }
 
-impl_setters! { MarkdownConfig;
-    trim_newlines: bool
+impl_builder_setters! { MarkdownConfigBuilder.config;
+    trim_newlines: bool,
+    skip_inline_parsing: bool,
+    highlight: bool,
+    attributes: bool,
+    allow_html: bool,
+    strip_html_comments: bool,
+    disallowed_html: DisallowedHtml,
+    heading_offset: usize,
+    heading_ids: bool,
+    transliterate_heading_ids: bool,
+    breaks: bool,
+    tables: bool,
+    image_dimensions: bool,
+    footnotes: bool,
+    definition_lists: bool,
+    strict_tables: bool,
+    strict_blockquotes: bool,
+    merge_adjacent_blockquotes: bool,
+    lenient_interruption: bool,
+    lenient_atx: bool,
+    thematic_break_min: usize,
+    tab_width: usize,
+    max_nesting_depth: usize,
+    max_reference_definitions: usize,
+    max_emphasis_delimiters: usize,
+    front_matter: Option<FrontMatterFormat>,
+    url_rewriter: Option<UrlRewriter>,
+    allowed_url_schemes: Option<Vec<String>>
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builder() {
+        let config = MarkdownConfig::builder().tables(true).max_nesting_depth(500).build().unwrap();
+        assert!(config.tables);
+        assert_eq!(config.max_nesting_depth, 500);
+        assert_eq!(config.tab_width, 4);
+    }
+
+    #[test]
+    fn builder_rejects_zero_tab_width() {
+        assert_eq!(MarkdownConfig::builder().tab_width(0).build().err(), Some(ConfigError::ZeroTabWidth));
+    }
+
+    #[test]
+    fn builder_rejects_comment_stripping_without_html() {
+        assert_eq!(MarkdownConfig::builder().strip_html_comments(true).build().err(),
+                   Some(ConfigError::StripCommentsWithoutHtml));
+    }
+
+    #[test]
+    fn builder_rejects_short_thematic_breaks() {
+        assert_eq!(MarkdownConfig::builder().thematic_break_min(2).build().err(),
+                   Some(ConfigError::ShortThematicBreakMin));
+        assert!(MarkdownConfig::builder().thematic_break_min(3).build().is_ok());
+    }
 }
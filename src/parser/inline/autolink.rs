@@ -0,0 +1,77 @@
+use parser::MarkdownParser;
+use tokens::*;
+use util::to_text;
+
+pub trait AutolinkParser {
+    fn parse_autolink(&self) -> Option<Inline>;
+}
+
+impl<'a> AutolinkParser for MarkdownParser<'a> {
+    // cursor is expected to be just after the opening `<`
+    fn parse_autolink(&self) -> Option<Inline> {
+        let m = self.cur.mark();
+        let pm = self.cur.phantom_mark();
+
+        // scheme
+        match opt_ret!(self.cur.next_byte()) {
+            c if (c as char).is_alphabetic() => {}
+            _ => return None
+        }
+        // the scan stops as soon as the scheme gets too long, so long runs
+        // after `<` are not read completely
+        let mut scheme_len = 1;
+        loop {
+            match opt_ret!(self.cur.next_byte()) {
+                b':' => break,
+                c if (c as char).is_alphanumeric() || one_of!(c, b'+', b'.', b'-') => {
+                    scheme_len += 1;
+                    if scheme_len > 32 { return None; }
+                }
+                _ => return None
+            }
+        }
+        if scheme_len < 2 { return None; }
+
+        // the rest may contain anything except spaces and angle brackets
+        loop {
+            match opt_ret!(self.cur.next_byte()) {
+                b'>' => break,
+                c if c <= b' ' || c == b'<' => return None,
+                _ => {}
+            }
+        }
+
+        // autolinks with schemes which are not allowed are left as text
+        let link = to_text(self.cur.slice_until_now_from(pm));
+        if !self.url_allowed(link.as_slice()) { return None; }
+        m.cancel();
+        Some(Link {
+            text: None,
+            link: Some(link),
+            title: None,
+            id: None,
+            reference: None
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::iter;
+    use parser::MarkdownParser;
+    use render::render_html;
+
+    #[test]
+    fn long_unterminated_autolink() {
+        let mut s = "<".to_string();
+        s.extend(iter::repeat('a').take(1 << 20));
+        let out = render_html(&MarkdownParser::from_str(s.as_slice()).read_all());
+        assert!(out.starts_with("<p>&lt;aaa"));
+        assert_eq!(out.len(), "<p>&lt;</p>\n".len() + (1 << 20));
+
+        let mut s = "<http:".to_string();
+        s.extend(iter::repeat('a').take(1 << 20));
+        let out = render_html(&MarkdownParser::from_str(s.as_slice()).read_all());
+        assert!(out.starts_with("<p>&lt;http:aaa"));
+    }
+}
@@ -1,40 +1,29 @@
+use std::cmp;
 use std::str;
-use std::borrow::ToOwned;
 
 use parser::{MarkdownParser, PhantomMark, Success, End, NoParse};
 use tokens::*;
-use util::CharOps;
-
-use super::InlineParser;
+use util::to_text;
 
+// code spans; emphasis and highlight are matched with delimiter runs
 pub trait EmphasisParser {
     fn parse_emphasis(&self, ec: u8, n: usize) -> Option<Inline>;
 }
 
 impl<'a> EmphasisParser for MarkdownParser<'a> {
     fn parse_emphasis(&self, ec: u8, n: usize) -> Option<Inline> {
-        debug!("reading emphasis, char [{}], n = {}", ec as char, n);
+        debug!("reading code span, char [{}], n = {}", ec as char, n);
         let pm = self.cur.phantom_mark();
         loop {
-            // a marker over the first character of closing emphasis
+            // a marker over the first character of the closing run
             let pm_last = opt_ret!(self.until_emph_closing(ec, n));
             let slice = self.cur.slice(pm, pm_last);
             debug!("checking slice: [{}], n: {}", ::std::str::from_utf8(slice).unwrap(), n);
 
-            // escaped closing emphasis
+            // a closing run right after a space does not end the span,
+            // the next one is tried instead
             if slice[slice.len()-1] != b' ' {
-                if ec.is_code() {  // this is code inline
-                    return Some(Code(str::from_utf8(slice).unwrap().to_owned()));
-                } else {
-                    let subp = self.fork(slice);
-                    let result = self.fix_links(subp.parse_inline());
-                    
-                    return Some(match n {
-                        1 => Emphasis(result),
-                        2 => MoreEmphasis(result),
-                        _ => unreachable!()  // for now
-                    });
-                }
+                return Some(Code(to_text(slice)));
             }
         }
     }
@@ -189,3 +178,268 @@ impl<'a> Ops<'a> for MarkdownParser<'a> {
         if pm_last == pm { None } else { Some(pm_last) }
     }
 }
+
+// A run of `*` or `_` characters, or `==` when highlight is enabled, which
+// may open or close emphasis.
+#[derive(Copy)]
+pub struct Delimiter {
+    pub idx: usize,  // index of the chunk with the run in the token list
+    ch: u8,
+    count: usize,  // characters not used by matched emphasis yet
+    orig: usize,
+    can_open: bool,
+    can_close: bool
+}
+
+impl Delimiter {
+    // checks whether the run `buf[start..end]` is left and/or right flanking
+    pub fn new(buf: &[u8], start: usize, end: usize, idx: usize) -> Delimiter {
+        let ch = buf[start];
+        let before = char_before(buf, start);
+        let after = char_after(buf, end);
+
+        let left = !is_whitespace(after) &&
+                   (!is_punctuation(after) || is_whitespace(before) || is_punctuation(before));
+        let right = !is_whitespace(before) &&
+                    (!is_punctuation(before) || is_whitespace(after) || is_punctuation(after));
+
+        // underscores do not work inside words
+        let (can_open, can_close) = if ch == b'_' {
+            (left && (!right || is_punctuation(before)), right && (!left || is_punctuation(after)))
+        } else {
+            (left, right)
+        };
+
+        Delimiter {
+            idx: idx,
+            ch: ch,
+            count: end - start,
+            orig: end - start,
+            can_open: can_open,
+            can_close: can_close
+        }
+    }
+}
+
+fn char_before(buf: &[u8], pos: usize) -> Option<char> {
+    let mut start = pos;
+    while start > 0 {
+        start -= 1;
+        // skip UTF-8 continuation bytes
+        if buf[start] & 0xC0 != 0x80 { break; }
+    }
+    str::from_utf8(&buf[start..pos]).ok().and_then(|s| s.chars().next())
+}
+
+fn char_after(buf: &[u8], pos: usize) -> Option<char> {
+    let end = cmp::min(pos + 4, buf.len());
+    (pos+1..end+1).filter_map(|e| str::from_utf8(&buf[pos..e]).ok()).next()
+        .and_then(|s| s.chars().next())
+}
+
+// buffer boundaries count as whitespace
+fn is_whitespace(c: Option<char>) -> bool {
+    match c {
+        Some(c) => c.is_whitespace(),
+        None => true
+    }
+}
+
+// ASCII punctuation and the most common Unicode punctuation blocks
+fn is_punctuation(c: Option<char>) -> bool {
+    match c {
+        Some(c) if c < '\x7f' => c > ' ' && !c.is_alphanumeric(),
+        Some(c) => match c as u32 {
+            0xA1 | 0xA7 | 0xAB | 0xB6 | 0xB7 | 0xBB | 0xBF => true,
+            0x2010...0x2027 | 0x2030...0x205E => true,  // general punctuation
+            0x2E00...0x2E7F => true,  // supplemental punctuation
+            0x3001...0x3003 | 0x3008...0x3011 | 0x3014...0x301F => true,  // CJK
+            0xFF01...0xFF0F | 0xFF1A...0xFF20 | 0xFF3B...0xFF40 | 0xFF5B...0xFF65 => true,
+            _ => false
+        },
+        None => false
+    }
+}
+
+// Matches delimiter runs as described in CommonMark spec, wrapping tokens
+// between matched runs into emphasis or highlight. Unmatched runs are left
+// as chunks.
+pub fn process_emphasis(tokens: &mut Vec<Inline>, mut delims: Vec<Delimiter>) {
+    let mut current = 0;
+    while current < delims.len() {
+        let closer = delims[current];
+        if !closer.can_close {
+            current += 1;
+            continue;
+        }
+
+        // "rule of 3": if one of the runs can both open and close, the sum
+        // of their lengths may not be a multiple of 3 unless both lengths are
+        let opener = (0..current).rev().find(|&j| {
+            let o = delims[j];
+            o.ch == closer.ch && o.can_open &&
+                !((o.can_close || closer.can_open) && (o.orig + closer.orig) % 3 == 0 &&
+                  !(o.orig % 3 == 0 && closer.orig % 3 == 0))
+        });
+
+        let j = match opener {
+            Some(j) => j,
+            None => {
+                // the closer may still be an opener for something after it
+                if closer.can_open { current += 1; } else { delims.remove(current); }
+                continue;
+            }
+        };
+
+        let opener = delims[j];
+        let n = if opener.count >= 2 && closer.count >= 2 { 2 } else { 1 };
+
+        // tokens between the runs become emphasis content
+        let rest = tokens.split_off(closer.idx);
+        let content = tokens.split_off(opener.idx + 1);
+        tokens.push(match (closer.ch, n) {
+            (b'=', _) => Highlight(content),
+            (_, 2) => MoreEmphasis(content),
+            _ => Emphasis(content)
+        });
+        tokens.extend(rest.into_iter());
+
+        // runs inside the content cannot match anything outside of it
+        for _ in j+1..current { delims.remove(j+1); }
+        current = j + 1;
+        let shift = closer.idx - (opener.idx + 2);
+        for d in delims[current..].iter_mut() { d.idx -= shift; }
+
+        shorten_run(tokens, &mut delims[j], n);
+        shorten_run(tokens, &mut delims[current], n);
+
+        if delims[current].count == 0 {
+            remove_run(tokens, &mut delims, current);
+        }
+        if delims[j].count == 0 {
+            remove_run(tokens, &mut delims, j);
+            current -= 1;
+        }
+    }
+}
+
+fn shorten_run(tokens: &mut Vec<Inline>, delim: &mut Delimiter, n: usize) {
+    delim.count -= n;
+    match &mut tokens[delim.idx] {
+        &mut Chunk(ref mut buf) => buf.truncate(delim.count),
+        _ => unreachable!()
+    }
+}
+
+fn remove_run(tokens: &mut Vec<Inline>, delims: &mut Vec<Delimiter>, i: usize) {
+    tokens.remove(delims[i].idx);
+    delims.remove(i);
+    for d in delims[i..].iter_mut() { d.idx -= 1; }
+}
+
+#[cfg(test)]
+mod tests {
+    use parser::{MarkdownParser, MarkdownConfig};
+    use render::render_html;
+    use tokens::*;
+
+    fn parse(s: &str, config: MarkdownConfig) -> Document {
+        MarkdownParser::from_str(s).with_config(config).read_all()
+    }
+
+    fn chunk(s: &str) -> Inline { Chunk(s.to_string()) }
+
+    #[test]
+    fn highlight() {
+        let doc = parse("==hi==", MarkdownConfig::default().highlight(true));
+        assert_eq!(doc, vec![Paragraph(vec![Highlight(vec![chunk("hi")])])]);
+        assert_eq!(render_html(&doc), "<p><mark>hi</mark></p>\n");
+    }
+
+    #[test]
+    fn highlight_disabled() {
+        assert_eq!(parse("==hi==", MarkdownConfig::default()),
+                   vec![Paragraph(vec![chunk("==hi==")])]);
+    }
+
+    #[test]
+    fn single_equals_sign_is_literal() {
+        assert_eq!(parse("=hi= ===hi===", MarkdownConfig::default().highlight(true)),
+                   vec![Paragraph(vec![chunk("=hi= ===hi===")])]);
+    }
+
+    #[test]
+    fn highlight_follows_flanking_rules() {
+        let config = MarkdownConfig::default().highlight(true);
+        assert_eq!(parse("a == b == c", config.clone()),
+                   vec![Paragraph(vec![chunk("a == b == c")])]);
+        assert_eq!(parse("==*a*== b", config),
+                   vec![Paragraph(vec![Highlight(vec![Emphasis(vec![chunk("a")])]), chunk(" b")])]);
+    }
+
+    fn html(s: &str) -> String {
+        render_html(&parse(s, MarkdownConfig::default()))
+    }
+
+    #[test]
+    fn rule_of_three() {
+        assert_eq!(html("*foo**bar**baz*"), "<p><em>foo<strong>bar</strong>baz</em></p>\n");
+        assert_eq!(html("*foo**bar*"), "<p><em>foo**bar</em></p>\n");
+        assert_eq!(html("***foo** bar*"), "<p><em><strong>foo</strong> bar</em></p>\n");
+        assert_eq!(html("*foo *bar**"), "<p><em>foo <em>bar</em></em></p>\n");
+        assert_eq!(html("foo***bar***baz"), "<p>foo<em><strong>bar</strong></em>baz</p>\n");
+        assert_eq!(html("foo******bar*********baz"),
+                   "<p>foo<strong><strong><strong>bar</strong></strong></strong>***baz</p>\n");
+        assert_eq!(html("*foo**bar***"), "<p><em>foo<strong>bar</strong></em></p>\n");
+    }
+
+    #[test]
+    fn punctuation_flanking() {
+        assert_eq!(html("foo-_(bar)_"), "<p>foo-<em>(bar)</em></p>\n");
+        assert_eq!(html("_(bar)_."), "<p><em>(bar)</em>.</p>\n");
+        assert_eq!(html("*(*foo*)*"), "<p><em>(<em>foo</em>)</em></p>\n");
+        assert_eq!(html("_foo_bar"), "<p>_foo_bar</p>\n");
+        assert_eq!(html("_foo_bar_baz_"), "<p><em>foo_bar_baz</em></p>\n");
+        assert_eq!(html("a*\"foo\"*"), "<p>a*&quot;foo&quot;*</p>\n");
+        assert_eq!(html("a\u{2014}_b_\u{2014}c"), "<p>a\u{2014}<em>b</em>\u{2014}c</p>\n");
+        assert_eq!(html("\u{ab}_foo_\u{bb}"), "<p>\u{ab}<em>foo</em>\u{bb}</p>\n");
+    }
+
+    #[test]
+    fn delimiter_cap() {
+        let config = MarkdownConfig::default().max_emphasis_delimiters(100);
+        let mut s = String::new();
+        for _ in 0..50000 { s.push_str("*_"); }
+        let out = render_html(&parse(s.as_slice(), config.clone()));
+        assert!(out.as_slice().split_str("<em>").count() <= 101);
+
+        let mut s = String::new();
+        for _ in 0..20000 { s.push_str("*a "); }
+        let s = s.as_slice().trim_right();
+        assert_eq!(parse(s, config), vec![Paragraph(vec![chunk(s)])]);
+    }
+
+    #[test]
+    fn code_spans_take_precedence() {
+        assert_eq!(parse("*a`b`c*", MarkdownConfig::default()), vec![Paragraph(vec![
+            Emphasis(vec![chunk("a"), Code("b".to_string()), chunk("c")])
+        ])]);
+        assert_eq!(html("*a`*`b"), "<p>*a<code>*</code>b</p>\n");
+        assert_eq!(html("`*a`*"), "<p><code>*a</code>*</p>\n");
+    }
+
+    #[test]
+    fn empty_runs_stay_literal() {
+        assert_eq!(html("foo ****"), "<p>foo ****</p>\n");
+        assert_eq!(html("****"), "<hr />\n");
+        assert_eq!(html("a ____ b"), "<p>a ____ b</p>\n");
+        assert_eq!(html("a ** ** b"), "<p>a ** ** b</p>\n");
+    }
+
+    #[test]
+    fn emphasis_across_soft_break() {
+        assert_eq!(parse("*a\nb*", MarkdownConfig::default()),
+                   vec![Paragraph(vec![Emphasis(vec![chunk("a\nb")])])]);
+        assert_eq!(html("x **a\nb** y"), "<p>x <strong>a\nb</strong> y</p>\n");
+    }
+}
@@ -1,5 +1,6 @@
 use parser::{MarkdownParser, ParseResult, Success, End};
 use tokens::*;
+use util::is_ascii_punctuation;
 
 pub trait EscapeParser {
     fn parse_escape(&self) -> ParseResult<Option<Inline>>;
@@ -7,13 +8,42 @@ pub trait EscapeParser {
 
 impl<'a> EscapeParser for MarkdownParser<'a> {
     fn parse_escape(&self) -> ParseResult<Option<Inline>> {
-        const ESCAPE_CHARS: &'static [u8] = b"\\`*_{}[]()#+-.!:|&<>^~";
-
         match self.cur.next_byte() {
-            Some(c) if ESCAPE_CHARS.contains(&c) => 
+            Some(c) if is_ascii_punctuation(c as char) => 
                 Success(Some(Chunk(String::from_utf8(vec![c]).unwrap()))),
+            // backslash at the end of a line is a hard break
+            Some(b'\n') if self.cur.available() => Success(Some(LineBreak)),
             Some(_) => Success(None),
             None => End
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use parser::MarkdownParser;
+    use tokens::*;
+
+    fn parse(s: &str) -> Document {
+        MarkdownParser::from_str(s).read_all()
+    }
+
+    #[test]
+    fn any_ascii_punctuation() {
+        assert_eq!(parse("\\\"a\\' \\@b \\= \\%\n"),
+                   vec![Paragraph(vec![Chunk("\"a' @b = %".to_string())])]);
+        assert_eq!(parse("\\a \\é\n"), vec![Paragraph(vec![Chunk("\\a \\é".to_string())])]);
+    }
+
+    #[test]
+    fn same_escapes_in_titles() {
+        let doc = parse("[\\\"a\\@](/u \"\\\"t\\@\")\n");
+        assert_eq!(doc, vec![Paragraph(vec![Link {
+            text: Some(vec![Chunk("\"a@".to_string())]),
+            link: Some("/u".to_string()),
+            title: Some("\"t@".to_string()),
+            id: None,
+            reference: None
+        }])]);
+    }
+}
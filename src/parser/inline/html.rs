@@ -0,0 +1,158 @@
+use std::ascii::AsciiExt;
+
+use parser::{MarkdownParser, Success};
+use tokens::*;
+use util::to_text;
+
+// tags whose content is not text, it is stripped together with the tags
+static RAW_TAGS: &'static [&'static str] = &["script", "style"];
+
+pub trait InlineHtmlParser {
+    fn parse_inline_html(&self) -> Option<Inline>;
+    // skips the content of a stripped raw tag up to and including its closing
+    // tag, or up to the end of the buffer if there is none
+    fn skip_raw_tag_content(&self, tag: &str);
+}
+
+trait Ops {
+    fn skip_tag_name(&self) -> Option<()>;
+    fn skip_attribute(&self) -> Option<()>;
+    fn skip_whitespace(&self) -> bool;
+}
+
+impl<'a> Ops for MarkdownParser<'a> {
+    fn skip_tag_name(&self) -> Option<()> {
+        match self.cur.next_byte() {
+            Some(c) if (c as char).is_alphabetic() => {}
+            _ => return None
+        }
+        while self.cur.current_byte().map(|c| (c as char).is_alphanumeric() || c == b'-')
+                                      .unwrap_or(false) {
+            self.cur.next();
+        }
+        Some(())
+    }
+
+    fn skip_attribute(&self) -> Option<()> {
+        // attribute name
+        match self.cur.next_byte() {
+            Some(c) if (c as char).is_alphabetic() || c == b'_' || c == b':' => {}
+            _ => return None
+        }
+        while self.cur.current_byte()
+                .map(|c| (c as char).is_alphanumeric() || one_of!(c, b'_', b'.', b':', b'-'))
+                .unwrap_or(false) {
+            self.cur.next();
+        }
+
+        // optional value
+        let m = self.cur.mark();
+        self.skip_whitespace();
+        if !self.try_read_char(b'=').is_success() {
+            m.reset();
+            return Some(());
+        }
+        m.cancel();
+        self.skip_whitespace();
+
+        match opt_ret!(self.cur.next_byte()) {
+            q @ b'"' | q @ b'\'' => loop {
+                match opt_ret!(self.cur.next_byte()) {
+                    c if c == q => break,
+                    _ => {}
+                }
+            },
+            c if one_of!(c, b' ', b'\n', b'"', b'\'', b'=', b'<', b'>', b'`') => return None,
+            _ => while self.cur.current_byte()
+                    .map(|c| !one_of!(c, b' ', b'\n', b'"', b'\'', b'=', b'<', b'>', b'`'))
+                    .unwrap_or(false) {
+                self.cur.next();
+            }
+        }
+        Some(())
+    }
+
+    fn skip_whitespace(&self) -> bool {
+        let mut skipped = false;
+        while self.cur.current_byte().map(|c| c == b' ' || c == b'\n').unwrap_or(false) {
+            self.cur.next();
+            skipped = true;
+        }
+        skipped
+    }
+}
+
+impl<'a> InlineHtmlParser for MarkdownParser<'a> {
+    // cursor is expected to be just after the opening `<`
+    fn parse_inline_html(&self) -> Option<Inline> {
+        let pm = self.cur.phantom_mark();
+
+        match opt_ret!(self.cur.current_byte()) {
+            // comment
+            b'!' => {
+                self.cur.next();
+                if !self.try_read_char(b'-').is_success() || !self.try_read_char(b'-').is_success() {
+                    return None;
+                }
+                let mut dashes = 0;
+                loop {
+                    match opt_ret!(self.cur.next_byte()) {
+                        b'-' => dashes += 1,
+                        b'>' if dashes >= 2 => break,
+                        _ => dashes = 0
+                    }
+                }
+            }
+
+            // closing tag
+            b'/' => {
+                self.cur.next();
+                opt_ret!(self.skip_tag_name());
+                self.skip_whitespace();
+                match self.try_read_char(b'>') {
+                    Success(_) => {}
+                    _ => return None
+                }
+            }
+
+            // opening tag
+            _ => {
+                opt_ret!(self.skip_tag_name());
+                loop {
+                    let had_whitespace = self.skip_whitespace();
+                    match opt_ret!(self.cur.next_byte()) {
+                        b'>' => break,
+                        b'/' => match self.try_read_char(b'>') {
+                            Success(_) => break,
+                            _ => return None
+                        },
+                        // attributes should be separated by whitespace
+                        _ if had_whitespace => {
+                            self.cur.prev();
+                            opt_ret!(self.skip_attribute());
+                        }
+                        _ => return None
+                    }
+                }
+            }
+        }
+
+        // include the opening `<` which is just before the initial position
+        let html = &self.cur.buf[pm.pos-1..self.cur.pos.get()];
+        Some(InlineHtml(to_text(html)))
+    }
+
+    fn skip_raw_tag_content(&self, tag: &str) {
+        let name = tag[1..].chars().take_while(|c| c.is_alphanumeric()).collect::<String>()
+            .to_ascii_lowercase();
+        if !RAW_TAGS.contains(&name.as_slice()) { return; }
+
+        let closing = format!("</{}", name);
+        let closing = closing.as_bytes();
+        let rest = &self.cur.buf[self.cur.pos.get()..];
+        let end = rest.windows(closing.len()).position(|w| w.eq_ignore_ascii_case(closing))
+            .and_then(|i| rest[i..].iter().position(|&c| c == b'>').map(|j| i + j + 1))
+            .unwrap_or(rest.len());
+        self.cur.advance(end);
+    }
+}
@@ -1,12 +1,18 @@
 use std::str;
-use std::borrow::ToOwned;
+use collections::BTreeMap;
 
-use parser::{MarkdownParser, Success, End, NoParse};
+use parser::{MarkdownParser, Success, End, NoParse, NestingLimitExceeded};
 use tokens::*;
-use util::{ByteSliceOps, CharOps};
+use util::{to_text, unescape, ByteSliceOps, CharOps};
+
+use super::InlineParser;
+
+// the longest link label allowed by CommonMark, in characters
+const MAX_LABEL_LENGTH: usize = 999;
 
 pub trait LinkParser {
     fn parse_link(&self, is_image: bool) -> Option<Inline>;
+    fn parse_footnote_reference(&self) -> Option<Inline>;
 }
 
 impl<'a> LinkParser for MarkdownParser<'a> {
@@ -14,22 +20,16 @@ impl<'a> LinkParser for MarkdownParser<'a> {
         let pm = self.cur.phantom_mark();
         let label;
 
-        // find matching closing brace
-        let mut escaping = false;
-        let mut level = 1usize;
-        loop {
-            let c = opt_ret!(self.cur.next_byte());
-            match c {
-                b'\\' => escaping = true,
-                _ if escaping => escaping = false,
-                b'[' => level += 1,
-                b']' => {
-                    level -= 1;
-                    if level <= 0 { break; }
-                }
-                _ => {}
+        // find matching closing bracket; brackets of the whole buffer are matched
+        // at once, so unmatched ones do not make parsing quadratic
+        let end = {
+            let mut brackets = self.brackets.borrow_mut();
+            if brackets.is_none() {
+                *brackets = Some(match_brackets(self.cur.buf));
             }
-        }
+            opt_ret!(brackets.as_ref().unwrap().get(&pm.pos).map(|&end| end))
+        };
+        self.cur.pos.set(end);
 
         label = self.cur.slice_until_now_from(pm);
         
@@ -44,6 +44,8 @@ impl<'a> LinkParser for MarkdownParser<'a> {
         let mut link = None;
         let mut title = None;
         let mut id = None;
+        let mut reference = None;
+        let mut dimensions = (None, None);
 
         match self.cur.current_byte() {
             Some(b'(') => {  // inline link
@@ -59,11 +61,11 @@ impl<'a> LinkParser for MarkdownParser<'a> {
                     let c = opt_ret!(self.cur.next_byte());
                     match c {
                         b'\\' => { self.cur.next(); },  // skip escaped char
-                        b'(' => level += 1,
-                        b')' => if level == 0 { break; } else { level -= 1; },
                         // encountered link title
-                        cc if (cc == b'\'' || cc == b'"') &&
+                        cc if one_of!(cc, b'\'', b'"', b'(') &&
                             self.cur.peek_before_prev().is_space() => break,
+                        b'(' => level += 1,
+                        b')' => if level == 0 { break; } else { level -= 1; },
                         _ => {}  // just pass through
                     }
                 }
@@ -73,7 +75,8 @@ impl<'a> LinkParser for MarkdownParser<'a> {
 
                 // read title, if it is there
                 let pc = self.cur.peek_prev();
-                if pc == b'\'' || pc == b'\"' {  // title
+                if pc == b'\'' || pc == b'\"' || pc == b'(' {  // title
+                    let close = if pc == b'(' { b')' } else { pc };
                     let pm = self.cur.phantom_mark();
 
                     let mut read_title = false;
@@ -81,7 +84,7 @@ impl<'a> LinkParser for MarkdownParser<'a> {
                         let c = opt_ret!(self.cur.next_byte());
                         match c {
                             b'\\' => { self.cur.next(); },  // skip escaped byte
-                            cc if cc == pc && !read_title => {
+                            cc if cc == close && !read_title => {
                                 title = Some(self.cur.slice_until_now_from(pm));
                                 read_title = true;
                             }
@@ -91,10 +94,23 @@ impl<'a> LinkParser for MarkdownParser<'a> {
                     }
                 }
                 
-                link = Some(
-                    link_slice.trim_right(|b: u8| b.is_space())
-                        .trim_left_one(b'<').trim_right_one(b'>')
-                );
+                let mut link_slice = link_slice.trim_right(|b: u8| b.is_space());
+                if is_image && self.config.image_dimensions {
+                    match split_dimensions(link_slice) {
+                        Some((rest, width, height)) => {
+                            link_slice = rest;
+                            dimensions = (width, height);
+                        }
+                        None => {}
+                    }
+                }
+
+                // only destinations in angle brackets may contain spaces
+                if link_slice.first() != Some(&b'<') && link_slice.iter().any(|&c| c.is_space()) {
+                    return None;
+                }
+
+                link = Some(link_slice.trim_left_one(b'<').trim_right_one(b'>'));
 
                 m.cancel();
             }
@@ -103,15 +119,25 @@ impl<'a> LinkParser for MarkdownParser<'a> {
                 self.cur.next();
                 let pm = self.cur.phantom_mark();
 
+                let mut length = 0;
                 loop {
                     let c = opt_ret!(self.cur.next_byte());
                     match c {
                         b']' => break,
+                        // continuation bytes are not counted, labels are limited in characters
+                        c if c & 0xC0 != 0x80 => {
+                            length += 1;
+                            if length > MAX_LABEL_LENGTH { return None; }
+                        }
                         _ => {}
                     }
                 }
 
-                id = Some(self.cur.slice_until_now_from(pm));
+                let slice = self.cur.slice_until_now_from(pm);
+                // the text is the label of collapsed references
+                if slice.is_empty() && label_length(label) > MAX_LABEL_LENGTH { return None; }
+                id = Some(slice);
+                reference = Some(if slice.is_empty() { CollapsedReference } else { FullReference });
 
                 m.cancel();
             }
@@ -119,27 +145,60 @@ impl<'a> LinkParser for MarkdownParser<'a> {
             _ => {  // shortcut reference link
                 m.reset();  // revert to the first character after ']'
 
+                if label_length(label) > MAX_LABEL_LENGTH { return None; }
                 id = Some(label);
+                reference = Some(ShortcutReference);
             }
         }
 
-        // TODO: parse link contents
-        let text = vec![Chunk(str::from_utf8(label).unwrap().to_owned())];
+        // escapes and entities are resolved in destinations and titles; links
+        // with schemes which are not allowed are left as text
+        let link = match link {
+            Some(link) => Some(opt_ret!(self.process_url(unescape(to_text(link).as_slice())))),
+            None => None
+        };
 
-        let link = link.map(|link| str::from_utf8(link).unwrap().to_owned());
-        let id = id.map(|id| str::from_utf8(id).unwrap().to_owned());
-        let title = title.map(|title| str::from_utf8(title).unwrap().to_owned());
+        // link texts nested deeper than the limit are kept as is
+        let text = if self.nesting_exceeded() {
+            self.warn(NestingLimitExceeded, self.source_range(pm),
+                      "link text nested too deeply".to_string());
+            vec![Chunk(to_text(label))]
+        } else {
+            self.fix_links(self.fork(label).parse_inline())
+        };
+
+        let id = id.map(|id| to_text(id));
+
+        // undefined references are found after the whole document is parsed
+        match id {
+            Some(ref id) => {
+                let label = if id.is_empty() {
+                    let mut buf = String::new();
+                    plain_text(&mut buf, &text);
+                    normalize_label(buf.as_slice())
+                } else {
+                    normalize_label(id.as_slice())
+                };
+                self.use_reference(label, self.source_range(pm));
+            }
+            None => {}
+        }
+        let title = title.map(|title| unescape(to_text(title).as_slice()));
 
         let link = if is_image {
             Image {
                 id: id,
+                reference: reference,
                 link: link,
                 title: title,
-                alt: text
+                alt: text,
+                width: dimensions.0,
+                height: dimensions.1
             }
         } else {
             Link {
                 id: id,
+                reference: reference,
                 link: link,
                 title: title,
                 text: Some(text)
@@ -148,4 +207,178 @@ impl<'a> LinkParser for MarkdownParser<'a> {
 
         Some(link)
     }
+    // `[^label]` right after the opening bracket
+    fn parse_footnote_reference(&self) -> Option<Inline> {
+        let m = self.cur.mark();
+        if self.cur.next_byte() != Some(b'^') { return None; }
+
+        let pm = self.cur.phantom_mark();
+        loop {
+            match opt_ret!(self.cur.next_byte()) {
+                b']' => break,
+                c if c.is_space() || c == b'\n' || c == b'[' => return None,
+                _ => {}
+            }
+        }
+        let label = self.cur.slice_until_now_from(pm);
+        if label.is_empty() { return None; }
+
+        m.cancel();
+        Some(FootnoteReference(to_text(label)))
+    }
+}
+
+// positions right after matching closing brackets by positions right after
+// opening ones; escaped brackets are skipped
+fn match_brackets(buf: &[u8]) -> BTreeMap<usize, usize> {
+    let mut pairs = BTreeMap::new();
+    let mut open = Vec::new();
+    let mut i = 0;
+    while i < buf.len() {
+        match buf[i] {
+            b'\\' => i += 1,
+            b'[' => open.push(i + 1),
+            b']' => match open.pop() {
+                Some(start) => { pairs.insert(start, i + 1); }
+                None => {}
+            },
+            _ => {}
+        }
+        i += 1;
+    }
+    pairs
+}
+
+// number of characters, i.e. bytes other than UTF-8 continuation bytes
+fn label_length(label: &[u8]) -> usize {
+    label.iter().filter(|&&c| c & 0xC0 != 0x80).count()
+}
+
+// splits `=WxH` off the end of an image destination, one of the dimensions may be omitted
+fn split_dimensions(link: &[u8]) -> Option<(&[u8], Option<usize>, Option<usize>)> {
+    let sep = opt_ret!(link.iter().rposition(|&c| c == b' '));
+    let spec = &link[sep+1..];
+    if spec.first() != Some(&b'=') { return None; }
+    let x = opt_ret!(spec.iter().position(|&c| c == b'x'));
+
+    fn dimension(s: &[u8]) -> Option<Option<usize>> {
+        if s.is_empty() { return Some(None); }
+        if !s.iter().all(|&c| c.is_numeric()) { return None; }
+        str::from_utf8(s).ok().and_then(|s| s.parse().ok()).map(Some)
+    }
+    let width = opt_ret!(dimension(&spec[1..x]));
+    let height = opt_ret!(dimension(&spec[x+1..]));
+    if width.is_none() && height.is_none() { return None; }
+
+    Some(((&link[..sep]).trim_right(|b: u8| b.is_space()), width, height))
+}
+
+#[cfg(test)]
+mod tests {
+    use parser::{MarkdownParser, MarkdownConfig, NestingLimitExceeded};
+    use render::render_html;
+    use tokens::*;
+
+    #[test]
+    fn deeply_nested_images() {
+        let mut s = String::new();
+        for _ in 0..50 { s.push_str("!["); }
+        s.push_str("a");
+        for _ in 0..50 { s.push_str("](x)"); }
+        let config = MarkdownConfig::default().max_nesting_depth(8);
+        let (doc, warnings) = MarkdownParser::from_str(s.as_slice()).with_config(config)
+            .read_all_with_warnings();
+        assert_eq!(warnings.iter().map(|w| w.kind).collect::<Vec<_>>(), vec![NestingLimitExceeded]);
+
+        // paragraph content is parsed at depth 1, so the eighth image keeps its alt as is
+        let mut rest = String::new();
+        for _ in 0..42 { rest.push_str("!["); }
+        rest.push_str("a");
+        for _ in 0..42 { rest.push_str("](x)"); }
+        let links = doc.links();
+        assert_eq!(links.len(), 8);
+        assert_eq!(links[7].text, Some(&vec![Chunk(rest.clone())]));
+        assert_eq!(plain_alt(&links[0]), rest);
+        assert_eq!(render_html(&doc), format!("<p><img src=\"x\" alt=\"{}\" /></p>\n", rest));
+    }
+
+    fn link(text: &str, link: &str) -> Inline {
+        Link {
+            text: Some(vec![Chunk(text.to_string())]),
+            link: Some(link.to_string()),
+            title: None,
+            id: None,
+            reference: None
+        }
+    }
+
+    #[test]
+    fn escaped_bracket_in_text() {
+        let doc = MarkdownParser::from_str("[a\\]b](/u)").read_all();
+        assert_eq!(doc, vec![Paragraph(vec![link("a]b", "/u")])]);
+    }
+
+    #[test]
+    fn escapes_in_destination() {
+        let doc = MarkdownParser::from_str("[a](/x%20y) [b](/p\\(q\\)&amp;r)").read_all();
+        assert_eq!(doc, vec![Paragraph(vec![
+            link("a", "/x%20y"), Chunk(" ".to_string()), link("b", "/p(q)&r")
+        ])]);
+        assert_eq!(render_html(&doc), "<p><a href=\"/x%20y\">a</a> <a href=\"/p(q)&amp;r\">b</a></p>\n");
+    }
+
+    fn image(width: Option<usize>, height: Option<usize>) -> Inline {
+        Image {
+            id: None,
+            reference: None,
+            link: Some("img.png".to_string()),
+            title: None,
+            alt: vec![Chunk("alt".to_string())],
+            width: width,
+            height: height
+        }
+    }
+
+    #[test]
+    fn image_dimensions() {
+        let config = MarkdownConfig::default().image_dimensions(true);
+        let doc = MarkdownParser::from_str("![alt](img.png =100x200)").with_config(config.clone())
+            .read_all();
+        assert_eq!(doc, vec![Paragraph(vec![image(Some(100), Some(200))])]);
+        assert_eq!(render_html(&doc),
+                   "<p><img src=\"img.png\" alt=\"alt\" width=\"100\" height=\"200\" /></p>\n");
+
+        let doc = MarkdownParser::from_str("![alt](img.png =100x)").with_config(config).read_all();
+        assert_eq!(doc, vec![Paragraph(vec![image(Some(100), None)])]);
+    }
+
+    #[test]
+    fn image_dimensions_disabled() {
+        let doc = MarkdownParser::from_str("![alt](img.png =100x200)").read_all();
+        assert_eq!(doc, vec![Paragraph(vec![Chunk("![alt](img.png =100x200)".to_string())])]);
+    }
+
+    #[test]
+    fn spaces_in_destinations() {
+        let parse = |s: &str| MarkdownParser::from_str(s).read_all();
+        assert_eq!(parse("[a](  /url  )"), vec![Paragraph(vec![link("a", "/url")])]);
+        assert_eq!(parse("[a](</url with space>)"),
+                   vec![Paragraph(vec![link("a", "/url with space")])]);
+        assert_eq!(parse("[a](/url with space)"),
+                   vec![Paragraph(vec![Chunk("[a](/url with space)".to_string())])]);
+    }
+
+    #[test]
+    fn long_link_texts() {
+        let text: String = ::std::iter::repeat('a').take(2000).collect();
+        let doc = MarkdownParser::from_str(format!("[{}](/u)", text).as_slice()).read_all();
+        assert_eq!(doc, vec![Paragraph(vec![link(text.as_slice(), "/u")])]);
+
+        let label: String = ::std::iter::repeat('a').take(1000).collect();
+        for source in [format!("[{}]", label), format!("[{}][]", label), format!("[a][{}]", label)].iter() {
+            let doc = MarkdownParser::from_str(source.as_slice()).read_all();
+            let short = |l: &LinkInfo| plain_alt(l).len() < 1000 && l.id.map(|id| id.len() < 1000).unwrap_or(true);
+            assert!(doc.links().iter().all(short), "{}", source);
+        }
+    }
 }
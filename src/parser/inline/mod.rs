@@ -1,14 +1,18 @@
-use parser::{MarkdownParser, MarkdownConfig, Cursor, PhantomMark, End};
+use parser::{MarkdownParser, MarkdownConfig, DisallowedHtml, Cursor, PhantomMark, Success, End};
 use tokens::*;
-use util::CharOps;
+use util::{CharOps, to_text};
 
-use self::emphasis::EmphasisParser;
+use self::emphasis::{EmphasisParser, Delimiter, process_emphasis};
 use self::escape::EscapeParser;
 use self::link::LinkParser;
+use self::html::InlineHtmlParser;
+use self::autolink::AutolinkParser;
 
 mod emphasis;
 mod escape;
 mod link;
+mod html;
+mod autolink;
 
 pub trait InlineParser {
     fn parse_inline(&self) -> Text;
@@ -16,6 +20,7 @@ pub trait InlineParser {
 
 struct InlineParsingState<'b, 'a: 'b> {
     tokens: Vec<Inline>,
+    delims: Vec<Delimiter>,
     cur: &'b Cursor<'a>,
     config: &'b MarkdownConfig,
     pm: PhantomMark,
@@ -30,9 +35,10 @@ impl<'b, 'a> InlineParsingState<'b, 'a> {
     }
 
     fn push_token(&mut self, mut token: Inline) {
-        fn is_chunk(token: Option<&Inline>) -> bool {
+        // delimiter runs are kept in separate chunks until emphasis is processed
+        fn is_chunk(token: Option<&Inline>, delims: &Vec<Delimiter>, len: usize) -> bool {
             match token {
-                Some(&Chunk(_)) => true,
+                Some(&Chunk(_)) => delims.last().map(|d| d.idx + 1 != len).unwrap_or(true),
                 _ => false
             }
         }
@@ -53,7 +59,7 @@ impl<'b, 'a> InlineParsingState<'b, 'a> {
         }
 
         match token {
-            Chunk(buf0) => if is_chunk(self.tokens.last()) {
+            Chunk(buf0) => if is_chunk(self.tokens.last(), &self.delims, self.tokens.len()) {
                 match self.tokens.last_mut().unwrap() {
                     &mut Chunk(ref mut buf) => buf.push_str(buf0.as_slice()),
                     _ => unreachable!()
@@ -73,8 +79,7 @@ impl<'b, 'a> InlineParsingState<'b, 'a> {
             if slice.is_empty() { return; }
 
             let chunk = slice.to_vec();
-            // TODO: handle UTF-8 decoding error
-            self.push_token(Chunk(String::from_utf8(chunk).unwrap()));
+            self.push_token(Chunk(to_text(chunk.as_slice())));
         }
 
         self.update();
@@ -91,11 +96,20 @@ impl<'b, 'a> InlineParsingState<'b, 'a> {
 impl<'a> InlineParser for MarkdownParser<'a> {
     fn parse_inline(&self) -> Text {
         debug!(">> parsing inline");
+        if self.config.skip_inline_parsing || self.headings_only {
+            let mut raw = to_text(&self.cur.buf[self.cur.pos.get()..]);
+            self.cur.pos.set(self.cur.buf.len());
+            if self.config.trim_newlines {
+                while raw.as_slice().ends_with("\n") { raw.pop(); }
+            }
+            return if raw.is_empty() { Vec::new() } else { vec![Raw(raw)] };
+        }
 
         let mut s = InlineParsingState {
             tokens: Vec::new(),
+            delims: Vec::new(),
             cur: &self.cur,
-            config: &self.config,
+            config: &*self.config,
             pm: self.cur.phantom_mark(),
             pm_last: self.cur.phantom_mark()
         };
@@ -104,20 +118,43 @@ impl<'a> InlineParser for MarkdownParser<'a> {
             debug!(">> cursor positon: {}", self.cur.pos.get());
             let c = opt_break!(self.cur.next_byte());
             match c {
-                b'\\' => match break_on_end!(self.parse_escape()).unwrap() {
-                    Some(token) => {
+                b'\\' => match self.parse_escape() {
+                    Success(Some(token)) => {
                         s.push_chunk();
                         s.push_token(token);
                         s.update();
                     }
-                    None => s.advance()
+                    // backslash at the end of the text is literal
+                    End => { s.advance(); break }
+                    _ => s.advance()
                 },
 
-                c if c.is_emphasis() || c.is_code() => {
-                    debug!(">> encountered emphasis");
+                c if c.is_emphasis() || c.is_highlight() && self.config.highlight => {
+                    debug!(">> encountered emphasis delimiter run");
+                    let start = self.cur.pos.get() - 1;
+                    while self.cur.current_byte() == Some(c) { self.cur.next(); }
+                    let end = self.cur.pos.get();
+
+                    // runs beyond the limit are left as text, as are `=` runs
+                    // other than `==`
+                    if s.delims.len() >= self.config.max_emphasis_delimiters ||
+                       c.is_highlight() && end - start != 2 {
+                        s.advance();
+                        continue;
+                    }
+
+                    s.push_chunk();
+
+                    s.delims.push(Delimiter::new(self.cur.buf, start, end, s.tokens.len()));
+                    s.tokens.push(Chunk(to_text(&self.cur.buf[start..end])));
+                    s.update();
+                }
+
+                c if c.is_code() => {
+                    debug!(">> encountered code span");
                     s.push_chunk();
 
-                    // one or two emphasis characters
+                    // one or two backticks
                     let mut n = 1;
                     if break_on_end!(self.try_read_char(c)).is_success() {
                         n += 1;
@@ -143,22 +180,89 @@ impl<'a> InlineParser for MarkdownParser<'a> {
                     debug!(">> encountered link start");
 
                     let is_image = self.cur.peek_before_prev_opt() == Some(b'!');
+                    let start = self.cur.pos.get() - if is_image { 2 } else { 1 };
                     if is_image { self.cur.retract(2); s.advance(); }
                     s.push_chunk();
                     if is_image { self.cur.advance(2); s.update(); }
 
-
+                    let footnote = if self.config.footnotes && !is_image {
+                        self.parse_footnote_reference()
+                    } else {
+                        None
+                    };
                     let m = self.cur.mark();
-                    match self.parse_link(is_image) {
+                    match footnote.or_else(|| self.parse_link(is_image)) {
                         Some(link) => {
                             m.cancel();
                             s.push_token(link);
+                            s.update();
                         }
+                        // unmatched brackets are left as text
                         None => {
                             m.reset();
+                            s.pm = PhantomMark { pos: start };
+                            s.advance();
                         }
                     }
-                    s.update();
+                }
+
+                b'<' => {
+                    debug!(">> encountered angle bracket");
+
+                    match self.parse_autolink() {
+                        Some(link) => {
+                            s.push_chunk();
+                            s.push_token(link);
+                            s.update();
+                        }
+                        None if self.config.allow_html ||
+                                self.config.disallowed_html == DisallowedHtml::Strip => {
+                            let m = self.cur.mark();
+                            match self.parse_inline_html() {
+                                Some(html) => {
+                                    m.cancel();
+                                    s.push_chunk();
+                                    match html {
+                                        // contents of scripts and styles are stripped too
+                                        InlineHtml(ref buf) if !self.config.allow_html =>
+                                            self.skip_raw_tag_content(buf.as_slice()),
+                                        InlineHtml(ref buf) if self.config.strip_html_comments &&
+                                                               buf.as_slice().starts_with("<!--") => {}
+                                        html => s.push_token(html)
+                                    }
+                                    s.update();
+                                }
+                                None => {
+                                    m.reset();
+                                    s.advance();
+                                }
+                            }
+                        }
+                        None => s.advance()
+                    }
+                }
+
+                // two or more spaces at the end of a line make a hard break,
+                // as does any newline when `breaks` is enabled
+                b'\n' if self.cur.available() => {
+                    let nl = self.cur.pos.get() - 1;
+                    let mut end = nl;
+                    while end > s.pm.pos && self.cur.buf[end-1] == b' ' { end -= 1; }
+
+                    if self.config.breaks || nl - end >= 2 {
+                        s.pm_last = PhantomMark { pos: end };
+                        s.push_chunk();
+                        s.push_token(LineBreak);
+                        s.update();
+                    } else if end < nl {
+                        // a space before a soft break is dropped
+                        s.pm_last = PhantomMark { pos: end };
+                        s.push_chunk();
+                        s.pm = PhantomMark { pos: nl };
+                        s.advance();
+                    } else {
+                        s.advance();
+                    }
                 }
 
                 // just advance
@@ -170,6 +274,67 @@ impl<'a> InlineParser for MarkdownParser<'a> {
             s.push_chunk();
         }
 
+        process_emphasis(&mut s.tokens, s.delims);
         s.tokens
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use parser::{MarkdownParser, MarkdownConfig};
+    use tokens::*;
+
+    fn parse(s: &str, config: MarkdownConfig) -> Document {
+        MarkdownParser::from_str(s).with_config(config).read_all()
+    }
+
+    fn chunk(s: &str) -> Inline { Chunk(s.to_string()) }
+
+    #[test]
+    fn breaks() {
+        assert_eq!(parse("a\nb", MarkdownConfig::default().breaks(true)),
+                   vec![Paragraph(vec![chunk("a"), LineBreak, chunk("b")])]);
+    }
+
+    #[test]
+    fn soft_breaks() {
+        assert_eq!(parse("a\nb", MarkdownConfig::default()), vec![Paragraph(vec![chunk("a\nb")])]);
+    }
+
+    #[test]
+    fn trailing_spaces_make_hard_break() {
+        assert_eq!(parse("a  \nb", MarkdownConfig::default()),
+                   vec![Paragraph(vec![chunk("a"), LineBreak, chunk("b")])]);
+    }
+
+    static STRUCTURE: &'static str =
+        "# a *b*\n\ntext [link](/u)\n\n- item `code`\n- item\n\n> quote\n\n```\ncode\n```\n\n***\n\nc\n---\n";
+
+    fn kinds(doc: &Document) -> Vec<&'static str> {
+        doc.iter().map(|b| match *b {
+            Heading { .. } => "heading",
+            Paragraph(_) => "paragraph",
+            UnorderedList { .. } | OrderedList { .. } => "list",
+            BlockQuote(_) => "quote",
+            BlockCode { .. } => "code",
+            HorizontalRule => "rule",
+            _ => "other"
+        }).collect()
+    }
+
+    #[test]
+    fn skipped_inline_parsing_keeps_blocks() {
+        let full = parse(STRUCTURE, MarkdownConfig::default());
+        let raw = parse(STRUCTURE, MarkdownConfig::default().skip_inline_parsing(true));
+        assert_eq!(kinds(&raw), kinds(&full));
+        assert_eq!(kinds(&raw), vec!["heading", "paragraph", "list", "quote", "code", "rule", "heading"]);
+        assert_eq!(raw[1], Paragraph(vec![Raw("text [link](/u)".to_string())]));
+    }
+
+    #[test]
+    fn backslash_hard_break() {
+        assert_eq!(parse("a\\\nb", MarkdownConfig::default()),
+                   vec![Paragraph(vec![chunk("a"), LineBreak, chunk("b")])]);
+        assert_eq!(parse("a\\", MarkdownConfig::default()), vec![Paragraph(vec![chunk("a\\")])]);
+    }
+}
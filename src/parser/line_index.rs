@@ -0,0 +1,86 @@
+use parser::MarkdownConfig;
+
+// Maps byte offsets in the source, e.g. in parse warnings, to lines and columns.
+pub struct LineIndex<'a> {
+    buf: &'a [u8],
+    line_starts: Vec<usize>,
+    tab_width: usize
+}
+
+impl<'a> LineIndex<'a> {
+    pub fn new(buf: &[u8]) -> LineIndex {
+        let mut line_starts = vec![0];
+        for (i, &c) in buf.iter().enumerate() {
+            if c == b'\n' { line_starts.push(i + 1); }
+        }
+
+        LineIndex {
+            buf: buf,
+            line_starts: line_starts,
+            tab_width: 4
+        }
+    }
+
+    // uses the tab width of the parser config
+    #[inline]
+    pub fn for_config<'b>(buf: &'b [u8], config: &MarkdownConfig) -> LineIndex<'b> {
+        LineIndex::new(buf).with_tab_width(config.tab_width)
+    }
+
+    // should be the same as in the parser config
+    #[inline]
+    pub fn with_tab_width(mut self, tab_width: usize) -> LineIndex<'a> {
+        self.tab_width = tab_width;
+        self
+    }
+
+    // 1-based line and column of the byte at `offset`; columns count characters
+    // with tabs expanded to the next tab stop, offsets past the end point to the end
+    pub fn line_col(&self, offset: usize) -> (usize, usize) {
+        let offset = ::std::cmp::min(offset, self.buf.len());
+        let line = match self.line_starts.binary_search(&offset) {
+            Ok(i) => i,
+            Err(i) => i - 1
+        };
+
+        let mut col = 0;
+        for &c in self.buf[self.line_starts[line]..offset].iter() {
+            match c {
+                b'\t' if self.tab_width > 0 => col += self.tab_width - col % self.tab_width,
+                // continuation bytes of multibyte characters
+                0x80...0xbf => {}
+                _ => col += 1
+            }
+        }
+
+        (line + 1, col + 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use parser::MarkdownConfig;
+    use super::*;
+
+    #[test]
+    fn line_col() {
+        let buf = b"ab\n\tc\n\ncaf\xc3\xa9 d\n";
+        let index = LineIndex::new(buf);
+        assert_eq!(index.line_col(0), (1, 1));
+        assert_eq!(index.line_col(2), (1, 3));
+        assert_eq!(index.line_col(3), (2, 1));
+        assert_eq!(index.line_col(4), (2, 5));
+        assert_eq!(index.line_col(6), (3, 1));
+        assert_eq!(index.line_col(7), (4, 1));
+        assert_eq!(index.line_col(13), (4, 6));
+        assert_eq!(index.line_col(1000), (5, 1));
+    }
+
+    #[test]
+    fn for_config() {
+        let buf = b"a\n\tb\n";
+        let config = MarkdownConfig::default().tab_width(2);
+        assert_eq!(LineIndex::for_config(buf, &config).line_col(3), (2, 3));
+        assert_eq!(LineIndex::for_config(buf, &MarkdownConfig::default()).line_col(3), (2, 5));
+    }
+}
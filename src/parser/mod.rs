@@ -1,15 +1,21 @@
-use std::collections::HashMap;
-use std::collections::VecDeque;
-use std::cell::{RefCell, Cell};
+use collections::{BTreeMap, BTreeSet};
+use collections::VecDeque;
+use core::cell::{RefCell, Cell};
+use alloc::rc::Rc;
 use std::ops::Deref;
+use std::convert::From;
+use std::ascii::AsciiExt;
 
 pub use self::config::*;
+pub use self::line_index::LineIndex;
 use tokens::*;
 
 pub use self::ParseResult::*;
+pub use self::WarningKind::*;
 use self::block::BlockParser;
+use self::inline::InlineParser;
 
-use util::{CellOps, ByteMatcher};
+use util::{CellOps, ByteMatcher, url_scheme};
 
 macro_rules! first_of {
     ($e:expr) => ($e);
@@ -103,6 +109,7 @@ macro_rules! cancel {
 }
 
 pub mod config;
+mod line_index;
 
 mod block;
 mod inline;
@@ -258,8 +265,46 @@ impl<'b, 'a> Mark<'b, 'a> {
 pub struct MarkdownParser<'a> {
     cur: Cursor<'a>,
     event_queue: RefCell<VecDeque<Block>>,
-    config: MarkdownConfig,
-    link_map: Option<LinkMap>
+    // shared with forked parsers
+    config: Rc<MarkdownConfig>,
+    link_map: Rc<RefCell<LinkMap>>,
+    warnings: Rc<RefCell<Vec<ParseWarning>>>,
+    // kinds, ranges and messages of `warnings`, to report each warning once
+    warning_keys: Rc<RefCell<BTreeSet<(WarningKind, usize, usize, String)>>>,
+    // whether a definition was ignored because of `max_reference_definitions`
+    definitions_exceeded: Rc<Cell<bool>>,
+    // normalized labels of reference links with their source ranges
+    reference_uses: Rc<RefCell<Vec<(String, (usize, usize))>>>,
+    // nesting level of block containers
+    depth: usize,
+    // inline content is parsed only in headings, see `headings`
+    headings_only: bool,
+    // range of the outermost block in the original buffer, used for
+    // warnings in forked parsers
+    range: (usize, usize),
+    // matching brackets of the buffer, computed when the first link is parsed
+    brackets: RefCell<Option<BTreeMap<usize, usize>>>,
+    // source column of the buffer start in block quotes, tab stops are counted from it
+    column: usize
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum WarningKind {
+    DuplicateDefinition,  // later definitions of the same label are ignored
+    TooManyDefinitions,   // definitions beyond `max_reference_definitions` are ignored, reported once
+    UndefinedReference,
+    UnterminatedFence,    // the code block continues to the end of its container
+    NestingLimitExceeded  // deeper content is parsed as text
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ParseWarning {
+    pub kind: WarningKind,
+    // byte range in the source; ranges inside block containers and of inline
+    // content are not exact, so the range of the outermost block is used
+    pub start: usize,
+    pub end: usize,
+    pub message: String
 }
 
 // public methods
@@ -269,29 +314,177 @@ impl<'a> MarkdownParser<'a> {
         MarkdownParser {
             cur: Cursor::new(buffer),
             event_queue: RefCell::new(VecDeque::new()),
-            config: MarkdownConfig::default(),
-            link_map: Some(HashMap::new())
+            config: Rc::new(MarkdownConfig::default()),
+            link_map: Rc::new(RefCell::new(BTreeMap::new())),
+            warnings: Rc::new(RefCell::new(Vec::new())),
+            warning_keys: Rc::new(RefCell::new(BTreeSet::new())),
+            definitions_exceeded: Rc::new(Cell::new(false)),
+            reference_uses: Rc::new(RefCell::new(Vec::new())),
+            depth: 0,
+            headings_only: false,
+            range: (0, 0),
+            brackets: RefCell::new(None),
+            column: 0
         }
     }
 
     #[inline]
-    pub fn with_config(mut self, config: MarkdownConfig) -> MarkdownParser<'a> {
+    pub fn from_str(s: &'a str) -> MarkdownParser<'a> {
+        MarkdownParser::new(s.as_bytes())
+    }
+
+    #[inline]
+    pub fn with_config(self, config: MarkdownConfig) -> MarkdownParser<'a> {
+        self.with_shared_config(Rc::new(config))
+    }
+
+    #[inline]
+    fn with_shared_config(mut self, config: Rc<MarkdownConfig>) -> MarkdownParser<'a> {
         self.config = config;
         self
     }
 
     #[inline]
     pub fn read_all(self) -> Document {
-        self.collect()
+        self.read_all_with_warnings().0
+    }
+
+    pub fn read_all_with_warnings(mut self) -> (Document, Vec<ParseWarning>) {
+        let mut doc: Document = self.by_ref().collect();
+        // links are fixed once more to resolve references to definitions
+        // following them
+        doc.fix_links(&*self.link_map.borrow());
+        if self.depth == 0 { self.check_references(&doc); }
+        (doc, self.warnings())
+    }
+
+    // Parses the next block and returns it with the number of bytes consumed.
+    // Parsing a block may also read the following one, e.g. a heading ending a
+    // paragraph; such a block is returned by the next call consuming nothing.
+    pub fn parse_one(&self) -> Option<(Block, usize)> {
+        let start = self.cur.pos.get();
+        let front = self.event_queue.borrow_mut().pop_front();
+        front.or_else(|| self.parse_block().to_option())
+            .map(|mut block| {
+                block.merge_chunks();
+                (block, self.cur.pos.get() - start)
+            })
+    }
+
+    // Rewinds the parser to the start of its buffer, forgetting definitions,
+    // warnings and enqueued blocks, so the buffer may be parsed again with
+    // the same configuration.
+    pub fn reset(&self) {
+        self.cur.pos.set(0);
+        self.event_queue.borrow_mut().clear();
+        self.link_map.borrow_mut().clear();
+        self.warnings.borrow_mut().clear();
+        self.warning_keys.borrow_mut().clear();
+        self.definitions_exceeded.set(false);
+        self.reference_uses.borrow_mut().clear();
+    }
+
+    // parses the whole buffer as inline content of a single block
+    pub fn read_inline(self) -> Text {
+        let mut text = self.fix_links(self.parse_inline());
+        text.merge_chunks();
+        text
+    }
+
+    // warnings found so far
+    #[inline]
+    pub fn warnings(&self) -> Vec<ParseWarning> {
+        self.warnings.borrow().clone()
+    }
+
+    // yields only top-level headings of the document; inline content of
+    // other blocks is not parsed
+    #[inline]
+    pub fn headings(mut self) -> Headings<'a> {
+        self.headings_only = true;
+        Headings { parser: self }
+    }
+}
+
+// string slices convert into parsers, e.g. `let parser: MarkdownParser = "# a".into();`
+impl<'a> From<&'a str> for MarkdownParser<'a> {
+    #[inline]
+    fn from(s: &'a str) -> MarkdownParser<'a> {
+        MarkdownParser::from_str(s)
+    }
+}
+
+// parses a whole document, a shortcut for `MarkdownParser` with a configuration
+pub fn parse_document(input: &str, config: &MarkdownConfig) -> Document {
+    MarkdownParser::from_str(input).with_config(config.clone()).read_all()
+}
+
+pub struct Headings<'a> {
+    parser: MarkdownParser<'a>
+}
+
+impl<'a> Iterator for Headings<'a> {
+    type Item = Block;
+
+    fn next(&mut self) -> Option<Block> {
+        loop {
+            match self.parser.next() {
+                Some(heading @ Heading { .. }) => return Some(heading),
+                Some(_) => {}
+                None => return None
+            }
+        }
+    }
+}
+
+// Holds configuration shared by all documents parsed with it
+pub struct MarkdownEngine {
+    config: Rc<MarkdownConfig>
+}
+
+impl MarkdownEngine {
+    #[inline]
+    pub fn new() -> MarkdownEngine {
+        MarkdownEngine {
+            config: Rc::new(MarkdownConfig::default())
+        }
+    }
+
+    #[inline]
+    pub fn with_config(mut self, config: MarkdownConfig) -> MarkdownEngine {
+        self.config = Rc::new(config);
+        self
+    }
+
+    #[inline]
+    pub fn config(&self) -> &MarkdownConfig {
+        &self.config
+    }
+
+    #[inline]
+    pub fn parse<'a>(&self, buffer: &'a [u8]) -> MarkdownParser<'a> {
+        MarkdownParser::new(buffer).with_shared_config(self.config.clone())
+    }
+
+    #[inline]
+    pub fn read_all(&self, buffer: &[u8]) -> Document {
+        self.parse(buffer).read_all()
+    }
+
+    #[inline]
+    pub fn parse_inlines(&self, buffer: &[u8]) -> Text {
+        self.parse(buffer).read_inline()
     }
 }
 
 impl<'a> Iterator for MarkdownParser<'a> {
     type Item = Block;
 
-    fn next(&mut self) -> Option<Block> { 
-        let front = self.event_queue.borrow_mut().pop_front();
-        front.or_else(|| self.parse_block().to_option())
+    // Blocks enqueued by a parse follow the block it returns, in the order they
+    // were enqueued; the queue is always drained before parsing further, so
+    // a parse never sees blocks enqueued by a previous one.
+    fn next(&mut self) -> Option<Block> {
+        self.parse_one().map(|(block, _)| block)
     }
 }
 
@@ -301,22 +494,120 @@ impl<'a> MarkdownParser<'a> {
         MarkdownParser {
             cur: Cursor::new(buffer),
             event_queue: RefCell::new(VecDeque::new()),
-            config: self.config,
-            link_map: None
+            config: self.config.clone(),
+            link_map: self.link_map.clone(),
+            warnings: self.warnings.clone(),
+            warning_keys: self.warning_keys.clone(),
+            definitions_exceeded: self.definitions_exceeded.clone(),
+            reference_uses: self.reference_uses.clone(),
+            depth: self.depth + 1,
+            headings_only: self.headings_only,
+            range: self.range,
+            brackets: RefCell::new(None),
+            column: self.column
+        }
+    }
+
+    // forks a parser for the contents of a block between `start` and `end`
+    fn fork_container<'b>(&self, buffer: &'b [u8], start: PhantomMark,
+                          end: PhantomMark) -> MarkdownParser<'b> {
+        let mut subp = self.fork(buffer);
+        if self.depth == 0 { subp.range = (start.pos, end.pos); }
+        subp
+    }
+
+    // parses heading content, which is parsed even when only headings are needed
+    fn parse_heading_inline(&self, buffer: &[u8], start: PhantomMark, end: PhantomMark) -> Text {
+        let mut subp = self.fork_container(buffer, start, end);
+        subp.headings_only = false;
+        self.fix_links(subp.parse_inline())
+    }
+
+    // range from `start` to the cursor position
+    fn source_range(&self, start: PhantomMark) -> (usize, usize) {
+        if self.depth == 0 { (start.pos, self.cur.pos.get()) } else { self.range }
+    }
+
+    fn warn(&self, kind: WarningKind, range: (usize, usize), message: String) {
+        // the same content may be parsed more than once, e.g. when a block
+        // is tried as an interruption of a paragraph
+        let key = (kind, range.0, range.1, message);
+        if self.warning_keys.borrow().contains(&key) { return; }
+        self.warnings.borrow_mut().push(ParseWarning {
+            kind: kind,
+            start: range.0,
+            end: range.1,
+            message: key.3.clone()
+        });
+        self.warning_keys.borrow_mut().insert(key);
+    }
+
+    fn define_reference(&self, label: String, ld: LinkDescription, start: PhantomMark) {
+        let range = self.source_range(start);
+        let mut link_map = self.link_map.borrow_mut();
+        if link_map.contains_key(&label) {
+            self.warn(DuplicateDefinition, range,
+                      format!("duplicate definition of reference `{}`", ld.id));
+        } else if link_map.len() >= self.config.max_reference_definitions {
+            if !self.definitions_exceeded.get() {
+                self.definitions_exceeded.set(true);
+                self.warn(TooManyDefinitions, range,
+                          format!("too many reference definitions, `{}` and later ones are ignored", ld.id));
+            }
+        } else {
+            link_map.insert(label, ld);
         }
     }
 
+    fn use_reference(&self, label: String, range: (usize, usize)) {
+        self.reference_uses.borrow_mut().push((label, range));
+    }
+
+    // warns about references which are still undefined in the whole document
+    fn check_references(&self, doc: &Document) {
+        let undefined = doc.unresolved_references();
+        for &(ref label, range) in self.reference_uses.borrow().iter() {
+            if undefined.contains(label) {
+                self.warn(UndefinedReference, range, format!("undefined reference `{}`", label));
+            }
+        }
+    }
+
+    // rewrites a link destination; destinations with schemes which are not
+    // allowed are rejected, and their links are left as text like autolinks
+    fn process_url(&self, link: String) -> Option<String> {
+        let link = match self.config.url_rewriter {
+            Some(ref rewrite) => rewrite(link.as_slice()),
+            None => link
+        };
+        if self.url_allowed(link.as_slice()) { Some(link) } else { None }
+    }
+
+    fn url_allowed(&self, url: &str) -> bool {
+        match (url_scheme(url), &self.config.allowed_url_schemes) {
+            (Some(scheme), &Some(ref allowed)) =>
+                allowed.iter().any(|s| s.as_slice().eq_ignore_ascii_case(scheme)),
+            _ => true
+        }
+    }
+
+    // lines with only spaces and tabs are empty
     fn try_parse_empty_line(&self) -> ParseResult<()> {
         let m = self.cur.mark();
         loop {
             match opt_ret_end!(self.cur.next_byte()) {
-                b' ' => {}
+                b' ' | b'\t' => {}
                 b'\n' => { m.cancel(); return Success(()) }
                 _ => return NoParse
             }
         }
     }
 
+    #[inline]
+    fn nesting_exceeded(&self) -> bool {
+        self.depth >= self.config.max_nesting_depth
+    }
+
     fn try_skip_initial_spaces(&self) -> ParseResult<()> {
         let mut n: u8 = 0;
         let m = self.cur.mark();
@@ -432,7 +723,7 @@ impl<'a> MarkdownParser<'a> {
 
     #[inline]
     fn fix_links<F: FixLinks>(&self, mut fl: F) -> F {
-        fl.fix_links_opt(self.link_map.as_ref());
+        fl.fix_links(&*self.link_map.borrow());
         fl
     }
 
@@ -519,3 +810,223 @@ impl<T> ParseResult<T> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use render::render_html;
+    use tokens::*;
+    use super::*;
+
+    fn chunk(s: &str) -> Inline { Chunk(s.to_string()) }
+
+    #[test]
+    fn engine() {
+        let engine = MarkdownEngine::new().with_config(MarkdownConfig::default().highlight(true));
+        assert!(engine.config().highlight);
+        assert_eq!(engine.read_all(b"==a=="), vec![Paragraph(vec![Highlight(vec![chunk("a")])])]);
+        assert_eq!(engine.read_all(b"==b=="), vec![Paragraph(vec![Highlight(vec![chunk("b")])])]);
+        assert_eq!(engine.parse(b"# c").collect::<Vec<_>>(), vec![Heading {
+            level: 1,
+            content: vec![chunk("c")],
+            attributes: None
+        }]);
+    }
+
+    #[test]
+    fn forked_parsers_share_config() {
+        let config = MarkdownConfig::default().rewrite_urls(|url| format!("/base/{}", url));
+        let doc = MarkdownParser::from_str("> - [a](x)\n").with_config(config).read_all();
+        let link = Link {
+            text: Some(vec![chunk("a")]),
+            link: Some("/base/x".to_string()),
+            title: None,
+            id: None,
+            reference: None
+        };
+        assert_eq!(doc, vec![BlockQuote(vec![UnorderedList {
+            items: vec![vec![Paragraph(vec![link])]],
+            tight: true
+        }])]);
+    }
+
+    fn heading(level: usize, content: Text, id: &str) -> Block {
+        let mut attributes = Attributes::new();
+        attributes.id = Some(id.to_string());
+        Heading { level: level, content: content, attributes: Some(attributes) }
+    }
+
+    #[test]
+    fn headings() {
+        let config = MarkdownConfig::default().heading_offset(1).heading_ids(true);
+        let source = "# One\n\ntext *a*\n\nTwo *b*\n---\n\n- # nested\n\n> quoted\n\n### Three\n";
+        let headings: Vec<_> = MarkdownParser::from_str(source).with_config(config).headings().collect();
+        assert_eq!(headings, vec![
+            heading(2, vec![chunk("One")], "one"),
+            heading(3, vec![chunk("Two "), Emphasis(vec![chunk("b")])], "two-b"),
+            heading(4, vec![chunk("Three")], "three")
+        ]);
+    }
+
+    #[test]
+    fn parse_inlines() {
+        let engine = MarkdownEngine::new();
+        assert_eq!(engine.parse_inlines(b"*a* `b`"),
+                   vec![Emphasis(vec![chunk("a")]), chunk(" "), Code("b".to_string())]);
+
+        let engine = MarkdownEngine::new()
+            .with_config(MarkdownConfig::default().highlight(true).allow_html(true));
+        assert_eq!(engine.parse_inlines(b"==a== <b>c</b>"), vec![
+            Highlight(vec![chunk("a")]), chunk(" "), InlineHtml("<b>".to_string()), chunk("c"),
+            InlineHtml("</b>".to_string())
+        ]);
+    }
+
+    #[test]
+    fn event_queue_is_fifo() {
+        let paragraph = |s: &str| Paragraph(vec![chunk(s)]);
+        let heading = |level: usize, s: &str| Heading {
+            level: level,
+            content: vec![chunk(s)],
+            attributes: None
+        };
+
+        // the heading ending the paragraph is enqueued and comes before anything parsed later
+        let parser = MarkdownParser::from_str("a\n# b\nc\n");
+        assert_eq!(parser.parse_one(), Some((paragraph("a"), 6)));
+        assert_eq!(parser.parse_one(), Some((heading(1, "b"), 0)));
+        assert_eq!(parser.parse_one(), Some((paragraph("c"), 2)));
+        assert_eq!(parser.parse_one(), None);
+
+        let doc = MarkdownParser::from_str("a\nb\n---\n***\nc\n# d\n").read_all();
+        assert_eq!(doc, vec![
+            paragraph("a"),
+            heading(2, "b"),
+            HorizontalRule,
+            paragraph("c"),
+            heading(1, "d")
+        ]);
+    }
+
+    fn warnings(s: &str, config: MarkdownConfig) -> Vec<(WarningKind, usize, usize)> {
+        MarkdownParser::from_str(s).with_config(config).read_all_with_warnings().1
+            .into_iter().map(|w| (w.kind, w.start, w.end)).collect()
+    }
+
+    #[test]
+    fn warning_kinds_and_ranges() {
+        let config = MarkdownConfig::default();
+        assert_eq!(warnings("a\n\n```\ncode\n", config.clone()), vec![(UnterminatedFence, 3, 12)]);
+        assert_eq!(warnings("x [a] y\n\nz\n", config.clone()), vec![(UndefinedReference, 0, 8)]);
+        assert_eq!(warnings("[a]: /x\n[a]: /y\n", config.clone()),
+                   vec![(DuplicateDefinition, 8, 16)]);
+        assert_eq!(warnings("> > > a\n", config.max_nesting_depth(2)),
+                   vec![(NestingLimitExceeded, 0, 8)]);
+    }
+
+    #[test]
+    fn warning_messages() {
+        let (_, warnings) = MarkdownParser::from_str("```\n[a]\n```\n[b]\n`").read_all_with_warnings();
+        assert_eq!(warnings.iter().map(|w| w.message.as_slice()).collect::<Vec<_>>(),
+                   vec!["undefined reference `b`"]);
+        let (_, warnings) = MarkdownParser::from_str("~~~\n").read_all_with_warnings();
+        assert_eq!(warnings[0].message, "unterminated code fence");
+    }
+
+    #[test]
+    fn parse_one() {
+        let parser = MarkdownParser::from_str("para one\nline\n\n# h\n");
+        assert_eq!(parser.parse_one(), Some((Paragraph(vec![chunk("para one\nline")]), 15)));
+        assert_eq!(parser.parse_one(), Some((Heading {
+            level: 1,
+            content: vec![chunk("h")],
+            attributes: None
+        }, 4)));
+        assert_eq!(parser.parse_one(), None);
+
+        // the heading is read with the paragraph
+        let parser = MarkdownParser::from_str("a\nb\n---\n");
+        assert_eq!(parser.parse_one().map(|(_, n)| n), Some(8));
+        assert_eq!(parser.parse_one().map(|(_, n)| n), Some(0));
+        assert_eq!(parser.parse_one(), None);
+    }
+
+    #[test]
+    fn allowed_url_schemes() {
+        let config = MarkdownConfig::default()
+            .allowed_url_schemes(Some(vec!["https".to_string(), "mailto".to_string()]));
+        let doc = MarkdownParser::from_str("[a](HTTPS://x.org) [b](mailto:m@x.org) [c](/rel) \
+                                            <https://x.org>")
+            .with_config(config.clone()).read_all();
+        let links: Vec<_> = doc.links().iter().map(|l| l.link.unwrap().to_string()).collect();
+        assert_eq!(links, vec!["HTTPS://x.org", "mailto:m@x.org", "/rel", "https://x.org"]);
+
+        // links with other schemes are left as text
+        let html = |s: &str| {
+            render_html(&MarkdownParser::from_str(s).with_config(config.clone()).read_all())
+        };
+        assert_eq!(html("[d](javascript:alert(1))"), "<p>[d](javascript:alert(1))</p>\n");
+        assert_eq!(html("![i](ftp://x.org/i.png)"), "<p>![i](ftp://x.org/i.png)</p>\n");
+        assert_eq!(html("<ftp://x.org>"), "<p>&lt;ftp://x.org&gt;</p>\n");
+        assert_eq!(html("[r], ![r]\n\n[r]: javascript:alert(1)\n"), "<p>[r], ![r]</p>\n");
+    }
+
+    #[test]
+    fn reset() {
+        let mut parser = MarkdownParser::from_str("[a]: /x\n\n[a] *b*\n\n```\n")
+            .with_config(MarkdownConfig::default().highlight(true));
+        let first: Vec<_> = parser.by_ref().collect();
+        let warnings = parser.warnings();
+        assert_eq!(warnings.len(), 1);
+
+        parser.reset();
+        assert!(parser.warnings().is_empty());
+        let second: Vec<_> = parser.by_ref().collect();
+        assert_eq!(second, first);
+        assert_eq!(parser.warnings(), warnings);
+        assert_eq!(parser.read_all(), Vec::new());
+    }
+
+    #[test]
+    fn blank_documents() {
+        assert!(MarkdownParser::new(b"").read_all().is_empty());
+        assert!(MarkdownParser::from_str("   \n\n").read_all().is_empty());
+        assert!(MarkdownParser::from_str("\t\n \t \n").read_all().is_empty());
+    }
+
+    #[test]
+    fn from_string_slice() {
+        use std::convert::{From, Into};
+        let parser: MarkdownParser = "# Title\n\nSome text\n".into();
+        let doc: Document = parser.collect();
+        assert_eq!(doc, vec![
+            Heading { level: 1, content: vec![chunk("Title")], attributes: None },
+            Paragraph(vec![chunk("Some text")])
+        ]);
+        assert_eq!(MarkdownParser::from("# Title\n\nSome text\n").read_all(), doc);
+    }
+
+    #[test]
+    fn parse_document_with_config() {
+        let doc = parse_document("# Title\n\n==text==\n", &MarkdownConfig::default().highlight(true));
+        assert_eq!(doc, vec![
+            Heading { level: 1, content: vec![chunk("Title")], attributes: None },
+            Paragraph(vec![Highlight(vec![chunk("text")])])
+        ]);
+    }
+
+    #[test]
+    fn definitions_iterate_in_label_order() {
+        let definitions = |s: &str| {
+            let mut parser = MarkdownParser::from_str(s);
+            let _: Vec<_> = parser.by_ref().collect();
+            let link_map = parser.link_map.borrow();
+            let result: Vec<_> = link_map.iter()
+                .map(|(label, ld)| (label.clone(), ld.link.clone())).collect();
+            result
+        };
+        let expected = vec![("a".to_string(), "/a".to_string()), ("b c".to_string(), "/b".to_string()),
+                            ("d".to_string(), "/d".to_string())];
+        assert_eq!(definitions("[D]: /d\n[a]: /a\n[B  c]: /b\n"), expected);
+        assert_eq!(definitions("[b C]: /b\n[d]: /d\n[A]: /a\n"), expected);
+    }
+}
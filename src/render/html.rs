@@ -0,0 +1,1198 @@
+use std::ascii::AsciiExt;
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::collections::BTreeMap;
+
+use tokens::*;
+use parser::{MarkdownConfig, parse_document};
+use util::is_absolute_url;
+
+use self::TableAlignment::*;
+use self::VoidElements::*;
+use self::FootnoteStyle::*;
+use self::SoftBreak::*;
+
+#[derive(Copy, PartialEq, Eq, Debug)]
+pub enum TableAlignment {
+    AlignAttribute,  // `align="center"`
+    StyleAttribute   // `style="text-align: center"`
+}
+
+// closing style of elements without content, i.e. `<br>`, `<hr>` and `<img>`
+#[derive(Copy, PartialEq, Eq, Debug)]
+pub enum VoidElements {
+    Html5,  // `<br>`
+    Xhtml   // `<br />`
+}
+
+#[derive(Copy, PartialEq, Eq, Debug)]
+pub enum FootnoteStyle {
+    Collected,  // numbered, in a `<section>` at the end with backreferences
+    Inlined     // in parentheses where they are referenced, e.g. for print; definitions
+                // with blocks other than paragraphs are still collected
+}
+
+// rendering of line breaks inside paragraphs which are not hard breaks
+#[derive(Copy, PartialEq, Eq, Debug)]
+pub enum SoftBreak {
+    Newline,  // as in the source
+    Space     // a single space, for text which was hard-wrapped
+}
+
+#[derive(Copy)]
+pub struct HtmlOptions {
+    pub line_numbers: bool,
+    pub table_alignment: TableAlignment,
+    // adds `target="_blank" rel="noopener"` to links with absolute urls
+    pub blank_target: bool,
+    pub void_elements: VoidElements,
+    // percent-encodes link destinations like CommonMark reference renderer does
+    pub percent_encode: bool,
+    // emits non-ASCII characters as numeric entities
+    pub ascii_safe_output: bool,
+    pub footnotes: FootnoteStyle,
+    // the output ends with a newline like CommonMark reference output does
+    pub trailing_newline: bool,
+    // runs of spaces in text outside of code are replaced with a single space
+    pub collapse_whitespace: bool,
+    // paragraphs with just an image become figures captioned with the alt text
+    pub figures: bool,
+    pub soft_break: SoftBreak,
+    // escapes raw HTML tags filtered by GFM, e.g. `<script>`
+    pub gfm_tagfilter: bool,
+    // rendering fails when the output grows larger than this, 0 means no limit
+    pub max_output_size: usize
+}
+
+impl HtmlOptions {
+    #[inline]
+    pub fn default() -> HtmlOptions {
+        HtmlOptions {
+            line_numbers: false,
+            table_alignment: AlignAttribute,
+            blank_target: false,
+            void_elements: Xhtml,
+            percent_encode: false,
+            ascii_safe_output: false,
+            footnotes: Collected,
+            trailing_newline: true,
+            collapse_whitespace: false,
+            figures: false,
+            soft_break: Newline,
+            gfm_tagfilter: false,
+            max_output_size: 0
+        }
+    }
+}
+
+impl_setters! { HtmlOptions;
+    line_numbers: bool,
+    table_alignment: TableAlignment,
+    blank_target: bool,
+    void_elements: VoidElements,
+    percent_encode: bool,
+    ascii_safe_output: bool,
+    footnotes: FootnoteStyle,
+    trailing_newline: bool,
+    collapse_whitespace: bool,
+    figures: bool,
+    soft_break: SoftBreak,
+    gfm_tagfilter: bool,
+    max_output_size: usize
+}
+
+#[derive(Copy, PartialEq, Eq, Debug)]
+pub enum RenderError {
+    OutputTooLarge
+}
+
+impl fmt::Display for RenderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            RenderError::OutputTooLarge => "rendered output exceeds the maximum size"
+        })
+    }
+}
+
+struct Footnotes {
+    definitions: BTreeMap<String, Document>,  // by normalized label
+    order: Vec<String>,   // labels in order of the first reference
+    active: Vec<String>   // labels of inline footnotes being rendered
+}
+
+pub struct HtmlRenderer {
+    options: HtmlOptions,
+    footnotes: RefCell<Footnotes>,
+    size_exceeded: Cell<bool>
+}
+
+// public methods
+impl HtmlRenderer {
+    #[inline]
+    pub fn new() -> HtmlRenderer {
+        HtmlRenderer {
+            options: HtmlOptions::default(),
+            footnotes: RefCell::new(Footnotes {
+                definitions: BTreeMap::new(),
+                order: Vec::new(),
+                active: Vec::new()
+            }),
+            size_exceeded: Cell::new(false)
+        }
+    }
+
+    #[inline]
+    pub fn with_options(mut self, options: HtmlOptions) -> HtmlRenderer {
+        self.options = options;
+        self
+    }
+
+    // convenience wrapper of `try_render` for unlimited output, which is the
+    // default; panics if the output exceeds `max_output_size`
+    pub fn render(&self, doc: &Document) -> String {
+        match self.try_render(doc) {
+            Ok(out) => out,
+            Err(e) => panic!("{}", e)
+        }
+    }
+
+    // fails when the output exceeds `max_output_size`; the size is checked before
+    // each block and piece of text, so the output may grow past the limit by the
+    // markup and escaping of one of them before rendering stops
+    pub fn try_render(&self, doc: &Document) -> Result<String, RenderError> {
+        self.size_exceeded.set(false);
+        {
+            let mut footnotes = self.footnotes.borrow_mut();
+            footnotes.definitions.clear();
+            footnotes.order.clear();
+            collect_footnotes(&mut footnotes.definitions, doc);
+        }
+
+        let mut out = String::new();
+        self.render_document(&mut out, doc);
+        // inlined footnotes may still have collected definitions
+        self.render_footnotes(&mut out);
+        self.finish(out)
+    }
+
+    // convenience wrapper of `try_render_single_block` for unlimited output;
+    // panics if the output exceeds `max_output_size`
+    pub fn render_single_block(&self, block: &Block, link_map: &LinkMap) -> String {
+        match self.try_render_single_block(block, link_map) {
+            Ok(out) => out,
+            Err(e) => panic!("{}", e)
+        }
+    }
+
+    // renders one block of a document, e.g. to update only an edited part of it;
+    // references are resolved with `link_map`, footnotes are left undefined;
+    // fails when the output exceeds `max_output_size`
+    pub fn try_render_single_block(&self, block: &Block, link_map: &LinkMap)
+                                   -> Result<String, RenderError> {
+        self.size_exceeded.set(false);
+        {
+            let mut footnotes = self.footnotes.borrow_mut();
+            footnotes.definitions.clear();
+            footnotes.order.clear();
+        }
+
+        let mut block = block.clone();
+        block.fix_links(link_map);
+        let mut out = String::new();
+        self.render_block(&mut out, &block);
+        self.finish(out)
+    }
+}
+
+#[inline]
+pub fn render_html(doc: &Document) -> String {
+    HtmlRenderer::new().render(doc)
+}
+
+#[inline]
+pub fn render_block_html(block: &Block, link_map: &LinkMap, options: HtmlOptions) -> String {
+    HtmlRenderer::new().with_options(options).render_single_block(block, link_map)
+}
+
+// parses and renders a whole document with default options
+#[inline]
+pub fn to_html(input: &str, config: &MarkdownConfig) -> String {
+    render_html(&parse_document(input, config))
+}
+
+// private methods
+impl HtmlRenderer {
+    fn finish(&self, mut out: String) -> Result<String, RenderError> {
+        if !self.options.trailing_newline {
+            while out.ends_with("\n") { out.pop(); }
+        }
+        if self.options.ascii_safe_output {
+            out = encode_non_ascii(out.as_slice());
+        }
+        if self.size_checked(out.len()) { Err(RenderError::OutputTooLarge) } else { Ok(out) }
+    }
+
+    // remembers whether the output would grow too large with `len` bytes,
+    // so the rest of the rendering is skipped
+    fn size_checked(&self, len: usize) -> bool {
+        let max = self.options.max_output_size;
+        if max > 0 && len > max {
+            self.size_exceeded.set(true);
+        }
+        self.size_exceeded.get()
+    }
+
+    fn render_document(&self, out: &mut String, doc: &Document) {
+        for block in doc.iter() {
+            if self.size_checked(out.len()) { return; }
+            self.render_block(out, block);
+        }
+    }
+
+    fn render_block(&self, out: &mut String, block: &Block) {
+        match *block {
+            Heading { level, ref content, ref attributes } => {
+                out.push_str(format!("<h{}", level).as_slice());
+                self.render_attributes(out, attributes);
+                out.push_str(">");
+                self.render_text(out, content);
+                out.push_str(format!("</h{}>\n", level).as_slice());
+            }
+
+            BlockQuote(ref content) => {
+                out.push_str("<blockquote>\n");
+                self.render_document(out, content);
+                out.push_str("</blockquote>\n");
+            }
+
+            BlockCode { ref content, ref attributes, .. } => {
+                out.push_str("<pre");
+                self.render_attributes(out, attributes);
+                out.push_str(">");
+                match block.language() {
+                    Some(language) => {
+                        out.push_str("<code class=\"language-");
+                        escape(out, language);
+                        out.push_str("\">");
+                    }
+                    None => out.push_str("<code>")
+                }
+                if self.options.line_numbers {
+                    self.render_numbered_lines(out, content.as_slice(), attributes);
+                } else {
+                    self.checked_escape(out, content.as_slice(), escape_code);
+                }
+                out.push_str("</code></pre>\n");
+            }
+
+            OrderedList { start_index, ref items, tight } => {
+                if start_index == 1 {
+                    out.push_str("<ol>\n");
+                } else {
+                    out.push_str(format!("<ol start=\"{}\">\n", start_index).as_slice());
+                }
+                self.render_list_items(out, items, tight);
+                out.push_str("</ol>\n");
+            }
+
+            UnorderedList { ref items, tight } => {
+                out.push_str("<ul>\n");
+                self.render_list_items(out, items, tight);
+                out.push_str("</ul>\n");
+            }
+
+            Paragraph(ref content) if self.options.figures && content.len() == 1 &&
+                                      is_captioned_image(&content[0]) => {
+                out.push_str("<figure>\n");
+                self.render_inline(out, &content[0]);
+                out.push_str("\n<figcaption>");
+                match content[0] {
+                    Image { ref alt, .. } => self.render_text(out, alt),
+                    _ => unreachable!()
+                }
+                out.push_str("</figcaption>\n</figure>\n");
+            }
+
+            Paragraph(ref content) => {
+                out.push_str("<p>");
+                self.render_text(out, content);
+                out.push_str("</p>\n");
+            }
+
+            HtmlBlock(ref content) => self.render_raw_html(out, content.as_slice()),
+
+            Table { ref alignments, ref header, ref rows } => {
+                out.push_str("<table>\n<thead>\n");
+                self.render_table_row(out, "th", alignments.as_slice(), header.as_slice());
+                out.push_str("</thead>\n");
+                if !rows.is_empty() {
+                    out.push_str("<tbody>\n");
+                    for row in rows.iter() {
+                        if self.size_checked(out.len()) { return; }
+                        self.render_table_row(out, "td", alignments.as_slice(), row.as_slice());
+                    }
+                    out.push_str("</tbody>\n");
+                }
+                out.push_str("</table>\n");
+            }
+
+            DefinitionList(ref items) => {
+                out.push_str("<dl>\n");
+                for &(ref term, ref definitions) in items.iter() {
+                    out.push_str("<dt>");
+                    self.render_text(out, term);
+                    out.push_str("</dt>\n");
+                    for definition in definitions.iter() {
+                        out.push_str("<dd>");
+                        match definition.first() {
+                            // a single paragraph is not wrapped, like in tight lists
+                            Some(&Paragraph(ref content)) if definition.len() == 1 =>
+                                self.render_text(out, content),
+                            _ => {
+                                out.push('\n');
+                                self.render_document(out, definition);
+                            }
+                        }
+                        out.push_str("</dd>\n");
+                    }
+                }
+                out.push_str("</dl>\n");
+            }
+
+            // metadata is not a part of the body, footnotes are rendered
+            // at the end or where they are referenced
+            FrontMatter { .. } | FootnoteDefinition { .. } => {}
+
+            HorizontalRule => {
+                out.push_str("<hr");
+                self.close_void(out);
+                out.push('\n');
+            }
+        }
+    }
+
+    fn render_numbered_lines(&self, out: &mut String, content: &str,
+                             attributes: &Option<Attributes>) {
+        // starting line number may be overridden with `linenostart` attribute
+        let start = attributes.as_ref()
+            .and_then(|a| a.data.iter().find(|&&(ref k, _)| k.as_slice() == "linenostart"))
+            .and_then(|&(_, ref v)| v.as_slice().parse().ok())
+            .unwrap_or(1usize);
+
+        for (i, line) in content.lines().enumerate() {
+            out.push_str(format!("<span class=\"line\" data-line=\"{}\">", start + i).as_slice());
+            self.checked_escape(out, line, escape_code);
+            out.push_str("</span>\n");
+        }
+    }
+
+    fn render_list_items(&self, out: &mut String, items: &[Document], tight: bool) {
+        for item in items.iter() {
+            out.push_str("<li>");
+            for block in item.iter() {
+                match *block {
+                    // only direct paragraphs of tight list items are unwrapped
+                    Paragraph(ref content) if tight => self.render_text(out, content),
+                    ref block => {
+                        if !out.ends_with("\n") { out.push('\n'); }
+                        self.render_block(out, block);
+                    }
+                }
+            }
+            out.push_str("</li>\n");
+        }
+    }
+
+    fn render_table_row(&self, out: &mut String, tag: &str, alignments: &[Alignment],
+                        cells: &[Text]) {
+        out.push_str("<tr>\n");
+        for (i, cell) in cells.iter().enumerate() {
+            out.push_str(format!("<{}", tag).as_slice());
+            let alignment = match alignments.get(i).map(|a| *a).unwrap_or(AlignNone) {
+                AlignNone => None,
+                AlignLeft => Some("left"),
+                AlignCenter => Some("center"),
+                AlignRight => Some("right")
+            };
+            match (alignment, self.options.table_alignment) {
+                (Some(a), AlignAttribute) => out.push_str(format!(" align=\"{}\"", a).as_slice()),
+                (Some(a), StyleAttribute) =>
+                    out.push_str(format!(" style=\"text-align: {}\"", a).as_slice()),
+                (None, _) => {}
+            }
+            out.push_str(">");
+            self.render_text(out, cell);
+            out.push_str(format!("</{}>\n", tag).as_slice());
+        }
+        out.push_str("</tr>\n");
+    }
+
+    fn render_text(&self, out: &mut String, text: &Text) {
+        for inline in text.iter() {
+            if self.size_checked(out.len()) { return; }
+            self.render_inline(out, inline);
+        }
+    }
+
+    fn render_inline(&self, out: &mut String, inline: &Inline) {
+        match *inline {
+            LineBreak => {
+                out.push_str("<br");
+                self.close_void(out);
+                out.push('\n');
+            }
+
+            Chunk(ref buf) if self.options.collapse_whitespace || self.options.soft_break == Space => {
+                let mut buf = buf.clone();
+                if self.options.soft_break == Space {
+                    buf = soft_breaks_to_spaces(buf.as_slice());
+                }
+                if self.options.collapse_whitespace {
+                    buf = collapse_spaces(buf.as_slice());
+                }
+                self.checked_escape(out, buf.as_slice(), escape);
+            }
+
+            Chunk(ref buf) | Raw(ref buf) => self.checked_escape(out, buf.as_slice(), escape),
+
+            Emphasis(ref content) => self.render_tagged(out, "em", content),
+
+            MoreEmphasis(ref content) => self.render_tagged(out, "strong", content),
+
+            Highlight(ref content) => self.render_tagged(out, "mark", content),
+
+            Code(ref buf) => {
+                out.push_str("<code>");
+                self.checked_escape(out, buf.as_slice(), escape_code);
+                out.push_str("</code>");
+            }
+
+            InlineHtml(ref buf) => self.render_raw_html(out, buf.as_slice()),
+
+            // undefined references are left as is, like undefined footnotes
+            Link { text: Some(ref text), link: None, id: Some(ref id), reference, .. } => {
+                out.push_str("[");
+                self.render_text(out, text);
+                out.push_str("]");
+                render_reference_label(out, reference, id.as_slice());
+            }
+
+            Image { ref alt, link: None, id: Some(ref id), reference, .. } => {
+                out.push_str("![");
+                self.render_text(out, alt);
+                out.push_str("]");
+                render_reference_label(out, reference, id.as_slice());
+            }
+
+            Link { ref text, ref link, ref title, .. } => {
+                out.push_str("<a href=\"");
+                self.render_url(out, link);
+                out.push_str("\"");
+                self.render_title(out, title);
+                match *link {
+                    Some(ref link) if self.options.blank_target && is_absolute_url(link.as_slice()) =>
+                        out.push_str(" target=\"_blank\" rel=\"noopener\""),
+                    _ => {}
+                }
+                out.push_str(">");
+                match *text {
+                    Some(ref text) => self.render_text(out, text),
+                    // automatic links show the link itself
+                    None => escape(out, link.as_ref().map(|s| s.as_slice()).unwrap_or(""))
+                }
+                out.push_str("</a>");
+            }
+
+            Image { ref alt, ref link, ref title, width, height, .. } => {
+                out.push_str("<img src=\"");
+                self.render_url(out, link);
+                out.push_str("\" alt=\"");
+                let mut alt_buf = String::new();
+                plain_text(&mut alt_buf, alt);
+                escape(out, alt_buf.as_slice());
+                out.push_str("\"");
+                self.render_title(out, title);
+                match width {
+                    Some(width) => out.push_str(format!(" width=\"{}\"", width).as_slice()),
+                    None => {}
+                }
+                match height {
+                    Some(height) => out.push_str(format!(" height=\"{}\"", height).as_slice()),
+                    None => {}
+                }
+                self.close_void(out);
+            }
+
+            FootnoteReference(ref id) => self.render_footnote_reference(out, id.as_slice())
+        }
+    }
+
+    fn render_footnote_reference(&self, out: &mut String, id: &str) {
+        let label = normalize_label(id);
+        let content = self.footnotes.borrow().definitions.get(&label).cloned();
+        let content = match content {
+            Some(content) => content,
+            // undefined footnotes are left as is
+            None => {
+                out.push_str("[^");
+                escape(out, id);
+                out.push_str("]");
+                return;
+            }
+        };
+
+        // definitions with other blocks than paragraphs do not fit into a `<span>`,
+        // so they are collected even when footnotes are inlined
+        let paragraphs_only = content.iter().all(|b| match *b { Paragraph(..) => true, _ => false });
+        let style = if paragraphs_only { self.options.footnotes } else { Collected };
+        match style {
+            Collected => {
+                let (n, first) = {
+                    let mut footnotes = self.footnotes.borrow_mut();
+                    match footnotes.order.iter().position(|l| *l == label) {
+                        Some(i) => (i + 1, false),
+                        None => {
+                            footnotes.order.push(label);
+                            (footnotes.order.len(), true)
+                        }
+                    }
+                };
+                // only the first reference is a target of the backreference
+                if first {
+                    out.push_str(format!("<sup class=\"footnote-ref\"><a href=\"#fn-{0}\" \
+                                          id=\"fnref-{0}\">{0}</a></sup>", n).as_slice());
+                } else {
+                    out.push_str(format!("<sup class=\"footnote-ref\"><a href=\"#fn-{0}\">\
+                                          {0}</a></sup>", n).as_slice());
+                }
+            }
+
+            Inlined => {
+                // footnotes referencing themselves are not expanded again
+                if self.footnotes.borrow().active.contains(&label) { return; }
+                self.footnotes.borrow_mut().active.push(label);
+                out.push_str("<span class=\"footnote\">(");
+                let mut first = true;
+                for block in content.iter() {
+                    match *block {
+                        Paragraph(ref text) => {
+                            if !first { out.push(' '); }
+                            self.render_text(out, text);
+                            first = false;
+                        }
+                        _ => {}
+                    }
+                }
+                out.push_str(")</span>");
+                self.footnotes.borrow_mut().active.pop();
+            }
+        }
+    }
+
+    fn render_footnotes(&self, out: &mut String) {
+        // definitions may reference other footnotes which are appended to the order;
+        // items go right to the output so that they count towards the size limit
+        let start = out.len();
+        out.push_str("<section class=\"footnotes\">\n<ol>\n");
+        let mut i = 0;
+        loop {
+            if self.size_checked(out.len()) { break; }
+            let content = {
+                let footnotes = self.footnotes.borrow();
+                match footnotes.order.get(i) {
+                    Some(label) => footnotes.definitions.get(label).unwrap().clone(),
+                    None => break
+                }
+            };
+            i += 1;
+
+            out.push_str(format!("<li id=\"fn-{}\">\n", i).as_slice());
+            let item_start = out.len();
+            self.render_document(out, &content);
+            let backref = format!("<a href=\"#fnref-{}\" class=\"footnote-backref\">\u{21a9}</a>", i);
+            // the backreference goes into the last paragraph if there is one
+            if out.as_slice()[item_start..].ends_with("</p>\n") {
+                let len = out.len() - "</p>\n".len();
+                out.truncate(len);
+                out.push(' ');
+                out.push_str(backref.as_slice());
+                out.push_str("</p>\n");
+            } else {
+                out.push_str(backref.as_slice());
+                out.push('\n');
+            }
+            out.push_str("</li>\n");
+        }
+
+        if i == 0 {
+            out.truncate(start);
+        } else {
+            out.push_str("</ol>\n</section>\n");
+        }
+    }
+
+    fn render_url(&self, out: &mut String, link: &Option<String>) {
+        let link = link.as_ref().map(|s| s.as_slice()).unwrap_or("");
+        if self.options.percent_encode {
+            escape(out, percent_encode(link).as_slice());
+        } else {
+            escape(out, link);
+        }
+    }
+
+    fn render_raw_html(&self, out: &mut String, html: &str) {
+        if self.size_checked(out.len() + html.len()) { return; }
+        if self.options.gfm_tagfilter {
+            filter_tags(out, html);
+        } else {
+            out.push_str(html);
+        }
+    }
+
+    // escaping only makes text longer, so the text itself should fit
+    fn checked_escape(&self, out: &mut String, s: &str, escape: fn(&mut String, &str)) {
+        if self.size_checked(out.len() + s.len()) { return; }
+        escape(out, s);
+    }
+
+    #[inline]
+    fn close_void(&self, out: &mut String) {
+        out.push_str(match self.options.void_elements {
+            Html5 => ">",
+            Xhtml => " />"
+        });
+    }
+
+    fn render_tagged(&self, out: &mut String, tag: &str, content: &Text) {
+        out.push_str(format!("<{}>", tag).as_slice());
+        self.render_text(out, content);
+        out.push_str(format!("</{}>", tag).as_slice());
+    }
+
+    fn render_attributes(&self, out: &mut String, attributes: &Option<Attributes>) {
+        let attributes = match *attributes {
+            Some(ref attributes) => attributes,
+            None => return
+        };
+
+        match attributes.id {
+            Some(ref id) => {
+                out.push_str(" id=\"");
+                escape(out, id.as_slice());
+                out.push_str("\"");
+            }
+            None => {}
+        }
+
+        if !attributes.classes.is_empty() {
+            out.push_str(" class=\"");
+            for (i, class) in attributes.classes.iter().enumerate() {
+                if i > 0 { out.push_str(" "); }
+                escape(out, class.as_slice());
+            }
+            out.push_str("\"");
+        }
+
+        for &(ref key, ref value) in attributes.data.iter() {
+            out.push_str(" data-");
+            escape(out, key.as_slice());
+            out.push_str("=\"");
+            escape(out, value.as_slice());
+            out.push_str("\"");
+        }
+    }
+
+    fn render_title(&self, out: &mut String, title: &Option<String>) {
+        match *title {
+            Some(ref title) => {
+                out.push_str(" title=\"");
+                escape(out, title.as_slice());
+                out.push_str("\"");
+            }
+            None => {}
+        }
+    }
+}
+
+// undefined image references are left as is, not put into figures
+fn is_captioned_image(inline: &Inline) -> bool {
+    match *inline {
+        Image { link: Some(_), ref alt, .. } => !alt.is_empty(),
+        _ => false
+    }
+}
+
+// the first definition of a label wins
+fn collect_footnotes(definitions: &mut BTreeMap<String, Document>, doc: &Document) {
+    for block in doc.iter() {
+        match *block {
+            FootnoteDefinition { ref id, ref content } => {
+                let label = normalize_label(id.as_slice());
+                if !definitions.contains_key(&label) {
+                    definitions.insert(label, content.clone());
+                }
+                collect_footnotes(definitions, content);
+            }
+            BlockQuote(ref content) => collect_footnotes(definitions, content),
+            OrderedList { ref items, .. } | UnorderedList { ref items, .. } =>
+                for item in items.iter() {
+                    collect_footnotes(definitions, item);
+                },
+            DefinitionList(ref items) =>
+                for &(_, ref content) in items.iter() {
+                    for item in content.iter() {
+                        collect_footnotes(definitions, item);
+                    }
+                },
+            _ => {}
+        }
+    }
+}
+
+// encodes everything except characters allowed in urls; existing
+// percent-encoded sequences are kept as is
+fn percent_encode(url: &str) -> String {
+    let bytes = url.as_bytes();
+    let is_hex = |i: usize| i < bytes.len() && match bytes[i] {
+        b'0'...b'9' | b'a'...b'f' | b'A'...b'F' => true,
+        _ => false
+    };
+
+    let mut result = String::new();
+    for (i, &b) in bytes.iter().enumerate() {
+        match b {
+            b'%' if is_hex(i+1) && is_hex(i+2) => result.push('%'),
+            b'a'...b'z' | b'A'...b'Z' | b'0'...b'9' => result.push(b as char),
+            b'-' | b'.' | b'_' | b'~' | b':' | b'/' | b'?' | b'#' | b'@' | b'!' | b'$' |
+            b'&' | b'\'' | b'(' | b')' | b'*' | b'+' | b',' | b';' | b'=' =>
+                result.push(b as char),
+            b => result.push_str(format!("%{:02X}", b).as_slice())
+        }
+    }
+    result
+}
+
+// a line break with the spaces around it, or several of them, become one space
+fn soft_breaks_to_spaces(s: &str) -> String {
+    let mut result = String::new();
+    let mut after_break = false;
+    for c in s.chars() {
+        match c {
+            '\n' => {
+                while result.ends_with(" ") { result.pop(); }
+                result.push(' ');
+                after_break = true;
+            }
+            ' ' if after_break => {}
+            c => {
+                result.push(c);
+                after_break = false;
+            }
+        }
+    }
+    result
+}
+
+fn collapse_spaces(s: &str) -> String {
+    let mut result = String::new();
+    for c in s.chars() {
+        if c != ' ' || !result.ends_with(" ") {
+            result.push(c);
+        }
+    }
+    result
+}
+
+fn encode_non_ascii(s: &str) -> String {
+    let mut result = String::new();
+    for c in s.chars() {
+        if (c as u32) < 0x80 {
+            result.push(c);
+        } else {
+            result.push_str(format!("&#x{:x};", c as u32).as_slice());
+        }
+    }
+    result
+}
+
+// tags which GFM tagfilter extension escapes even when HTML is allowed
+static FILTERED_TAGS: &'static [&'static str] = &[
+    "title", "textarea", "style", "xmp", "iframe", "noembed", "noframes", "script", "plaintext"
+];
+
+// replaces `<` of opening and closing filtered tags with `&lt;`
+fn filter_tags(out: &mut String, html: &str) {
+    let mut rest = html;
+    loop {
+        match rest.find('<') {
+            Some(idx) => {
+                out.push_str(&rest[..idx]);
+                rest = &rest[idx+1..];
+                out.push_str(if is_filtered_tag(rest) { "&lt;" } else { "<" });
+            }
+            None => {
+                out.push_str(rest);
+                return;
+            }
+        }
+    }
+}
+
+// `s` starts just after `<`; the tag name should be followed by whitespace, `>` or `/>`
+fn is_filtered_tag(s: &str) -> bool {
+    let s = if s.starts_with("/") { &s[1..] } else { s };
+    let bytes = s.as_bytes();
+    FILTERED_TAGS.iter().any(|tag| {
+        let n = tag.len();
+        bytes.len() >= n && bytes[..n].eq_ignore_ascii_case(tag.as_bytes()) &&
+            match bytes.get(n) {
+                None => false,
+                Some(&b'>') => true,
+                Some(&b'/') => bytes.get(n+1) == Some(&b'>'),
+                Some(&c) => c == b' ' || c == b'\t' || c == b'\n'
+            }
+    })
+}
+
+// code is text content, so quotes are left as is
+fn escape_code(out: &mut String, s: &str) {
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            c => out.push(c)
+        }
+    }
+}
+
+fn escape(out: &mut String, s: &str) {
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            c => out.push(c)
+        }
+    }
+}
+
+// the label part of an undefined reference: nothing for shortcut references,
+// `[]` for collapsed ones and the label itself for full ones
+fn render_reference_label(out: &mut String, reference: Option<ReferenceKind>, id: &str) {
+    match reference {
+        Some(ShortcutReference) => {}
+        Some(CollapsedReference) => out.push_str("[]"),
+        // references built by hand may lack the kind, their label is written out
+        Some(FullReference) | None => {
+            out.push_str("[");
+            escape(out, id);
+            out.push_str("]");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use parser::{MarkdownParser, MarkdownConfig};
+    use tokens::*;
+    use super::*;
+
+    fn render(s: &str, config: MarkdownConfig, options: HtmlOptions) -> String {
+        let doc = MarkdownParser::from_str(s).with_config(config).read_all();
+        HtmlRenderer::new().with_options(options).render(&doc)
+    }
+
+    #[test]
+    fn line_numbers() {
+        let options = HtmlOptions::default().line_numbers(true);
+        assert_eq!(render("```\na\nb\nc\n```\n", MarkdownConfig::default(), options),
+                   "<pre><code><span class=\"line\" data-line=\"1\">a</span>\n\
+                    <span class=\"line\" data-line=\"2\">b</span>\n\
+                    <span class=\"line\" data-line=\"3\">c</span>\n</code></pre>\n");
+    }
+
+    #[test]
+    fn line_numbers_start() {
+        let options = HtmlOptions::default().line_numbers(true);
+        assert_eq!(render("```{linenostart=5}\na\n```\n", MarkdownConfig::default().attributes(true),
+                          options),
+                   "<pre data-linenostart=\"5\"><code><span class=\"line\" data-line=\"5\">a</span>\n\
+                    </code></pre>\n");
+    }
+
+    #[test]
+    fn tight_list_keeps_nested_paragraphs() {
+        assert_eq!(render("- a\n  > b\n- c\n", MarkdownConfig::default(), HtmlOptions::default()),
+                   "<ul>\n<li>a\n<blockquote>\n<p>b</p>\n</blockquote>\n</li>\n<li>c</li>\n</ul>\n");
+    }
+
+    static ALIGNED_TABLE: &'static str = "a | b | c | d\n:-- | :-: | --: | ---\n1 | 2 | 3 | 4\n";
+
+    #[test]
+    fn table_align_attributes() {
+        let config = MarkdownConfig::default().tables(true);
+        assert_eq!(render(ALIGNED_TABLE, config, HtmlOptions::default()),
+                   "<table>\n<thead>\n<tr>\n<th align=\"left\">a</th>\n\
+                    <th align=\"center\">b</th>\n<th align=\"right\">c</th>\n<th>d</th>\n\
+                    </tr>\n</thead>\n<tbody>\n<tr>\n<td align=\"left\">1</td>\n\
+                    <td align=\"center\">2</td>\n<td align=\"right\">3</td>\n<td>4</td>\n\
+                    </tr>\n</tbody>\n</table>\n");
+    }
+
+    #[test]
+    fn table_style_attributes() {
+        let config = MarkdownConfig::default().tables(true);
+        let options = HtmlOptions::default().table_alignment(TableAlignment::StyleAttribute);
+        assert_eq!(render(ALIGNED_TABLE, config, options),
+                   "<table>\n<thead>\n<tr>\n<th style=\"text-align: left\">a</th>\n\
+                    <th style=\"text-align: center\">b</th>\n\
+                    <th style=\"text-align: right\">c</th>\n<th>d</th>\n\
+                    </tr>\n</thead>\n<tbody>\n<tr>\n<td style=\"text-align: left\">1</td>\n\
+                    <td style=\"text-align: center\">2</td>\n\
+                    <td style=\"text-align: right\">3</td>\n<td>4</td>\n\
+                    </tr>\n</tbody>\n</table>\n");
+    }
+
+    #[test]
+    fn title_with_both_quotes() {
+        assert_eq!(render("[a](/u \"x \\\"y\\\" 'z'\")", MarkdownConfig::default(), HtmlOptions::default()),
+                   "<p><a href=\"/u\" title=\"x &quot;y&quot; 'z'\">a</a></p>\n");
+    }
+
+    #[test]
+    fn blank_target() {
+        let options = HtmlOptions::default().blank_target(true);
+        assert_eq!(render("[a](https://x.org) [b](/rel) [c](//cdn.org)", MarkdownConfig::default(),
+                          options),
+                   "<p><a href=\"https://x.org\" target=\"_blank\" rel=\"noopener\">a</a> \
+                    <a href=\"/rel\">b</a> \
+                    <a href=\"//cdn.org\" target=\"_blank\" rel=\"noopener\">c</a></p>\n");
+    }
+
+    static VOID_ELEMENTS: &'static str = "a  \nb ![c](d.png)\n\n***\n";
+
+    #[test]
+    fn xhtml_void_elements() {
+        assert_eq!(render(VOID_ELEMENTS, MarkdownConfig::default(), HtmlOptions::default()),
+                   "<p>a<br />\nb <img src=\"d.png\" alt=\"c\" /></p>\n<hr />\n");
+    }
+
+    #[test]
+    fn html5_void_elements() {
+        let options = HtmlOptions::default().void_elements(VoidElements::Html5);
+        assert_eq!(render(VOID_ELEMENTS, MarkdownConfig::default(), options),
+                   "<p>a<br>\nb <img src=\"d.png\" alt=\"c\"></p>\n<hr>\n");
+    }
+
+    #[test]
+    fn code_escaping() {
+        assert_eq!(render("    <script>&amp;\"x\"\n\n`<b>&amp;\"y\"` *a*", MarkdownConfig::default(),
+                          HtmlOptions::default()),
+                   "<pre><code>&lt;script&gt;&amp;amp;\"x\"\n</code></pre>\n\
+                    <p><code>&lt;b&gt;&amp;amp;\"y\"</code> <em>a</em></p>\n");
+        assert_eq!(render("```\n\"x\" < y\n```\n", MarkdownConfig::default(),
+                          HtmlOptions::default().line_numbers(true)),
+                   "<pre><code><span class=\"line\" data-line=\"1\">\"x\" &lt; y</span>\n</code></pre>\n");
+    }
+
+    #[test]
+    fn unbalanced_brackets() {
+        let render = |s: &str| render(s, MarkdownConfig::default(), HtmlOptions::default());
+        assert_eq!(render("a [ b"), "<p>a [ b</p>\n");
+        assert_eq!(render("a ] b"), "<p>a ] b</p>\n");
+        assert_eq!(render("[a][b"), "<p>[a][b</p>\n");
+    }
+
+    #[test]
+    fn undefined_references() {
+        let render = |s: &str| render(s, MarkdownConfig::default(), HtmlOptions::default());
+        assert_eq!(render("[x] [*y*][] [z][w] ![v]"),
+                   "<p>[x] [<em>y</em>][] [z][w] ![v]</p>\n");
+        assert_eq!(render("[a][a], ![b][b]"), "<p>[a][a], ![b][b]</p>\n");
+        assert_eq!(render("[x]\n\n[x]: /url"), "<p><a href=\"/url\">x</a></p>\n");
+    }
+
+    #[test]
+    fn long_unmatched_bracket() {
+        let mut s: String = ::std::iter::repeat('[').take(1000).collect();
+        s.extend(::std::iter::repeat('a').take(10000));
+        let out = render(s.as_slice(), MarkdownConfig::default(), HtmlOptions::default());
+        assert!(out.starts_with("<p>[[["));
+    }
+
+    #[test]
+    fn percent_encoded_destinations() {
+        let s = "[a](<b c>) [d](/e%20f) [g](/ä?x=1&y)";
+        assert_eq!(render(s, MarkdownConfig::default(), HtmlOptions::default().percent_encode(true)),
+                   "<p><a href=\"b%20c\">a</a> <a href=\"/e%20f\">d</a> \
+                    <a href=\"/%C3%A4?x=1&amp;y\">g</a></p>\n");
+        assert_eq!(render(s, MarkdownConfig::default(), HtmlOptions::default()),
+                   "<p><a href=\"b c\">a</a> <a href=\"/e%20f\">d</a> \
+                    <a href=\"/ä?x=1&amp;y\">g</a></p>\n");
+    }
+
+    #[test]
+    fn ascii_safe_output() {
+        let options = HtmlOptions::default().ascii_safe_output(true);
+        assert_eq!(render("café `é` [a](/é)", MarkdownConfig::default(), options),
+                   "<p>caf&#xe9; <code>&#xe9;</code> <a href=\"/&#xe9;\">a</a></p>\n");
+        assert_eq!(render("café", MarkdownConfig::default(), HtmlOptions::default()),
+                   "<p>café</p>\n");
+    }
+
+    static FOOTNOTES: &'static str = "a[^1] b[^1] c[^x]\n\n[^1]: note\n";
+
+    #[test]
+    fn collected_footnotes() {
+        let config = MarkdownConfig::default().footnotes(true);
+        assert_eq!(render(FOOTNOTES, config, HtmlOptions::default()),
+                   "<p>a<sup class=\"footnote-ref\"><a href=\"#fn-1\" id=\"fnref-1\">1</a></sup> \
+                    b<sup class=\"footnote-ref\"><a href=\"#fn-1\">1</a></sup> c[^x]</p>\n\
+                    <section class=\"footnotes\">\n<ol>\n<li id=\"fn-1\">\n\
+                    <p>note <a href=\"#fnref-1\" class=\"footnote-backref\">\u{21a9}</a></p>\n\
+                    </li>\n</ol>\n</section>\n");
+    }
+
+    #[test]
+    fn inlined_footnotes() {
+        let config = MarkdownConfig::default().footnotes(true);
+        let options = HtmlOptions::default().footnotes(FootnoteStyle::Inlined);
+        assert_eq!(render(FOOTNOTES, config, options),
+                   "<p>a<span class=\"footnote\">(note)</span> \
+                    b<span class=\"footnote\">(note)</span> c[^x]</p>\n");
+    }
+
+    #[test]
+    fn inlined_footnotes_with_blocks() {
+        let config = MarkdownConfig::default().footnotes(true);
+        let options = HtmlOptions::default().footnotes(FootnoteStyle::Inlined);
+        let source = "a[^1] b[^2]\n\n[^1]: one\n\n    two\n\n[^2]: code:\n\n        let x;\n";
+        assert_eq!(render(source, config, options),
+                   "<p>a<span class=\"footnote\">(one two)</span> \
+                    b<sup class=\"footnote-ref\"><a href=\"#fn-1\" id=\"fnref-1\">1</a></sup></p>\n\
+                    <section class=\"footnotes\">\n<ol>\n<li id=\"fn-1\">\n\
+                    <p>code:</p>\n<pre><code>let x;\n</code></pre>\n\
+                    <a href=\"#fnref-1\" class=\"footnote-backref\">\u{21a9}</a>\n\
+                    </li>\n</ol>\n</section>\n");
+    }
+
+    #[test]
+    fn trailing_newline() {
+        assert_eq!(render("a", MarkdownConfig::default(), HtmlOptions::default()), "<p>a</p>\n");
+        assert_eq!(render("a\n\n", MarkdownConfig::default(),
+                          HtmlOptions::default().trailing_newline(false)),
+                   "<p>a</p>");
+    }
+
+    #[test]
+    fn collapse_whitespace() {
+        let options = HtmlOptions::default().collapse_whitespace(true);
+        assert_eq!(render("a    b `c    d`\n\n    e    f\n", MarkdownConfig::default(), options),
+                   "<p>a b <code>c    d</code></p>\n<pre><code>e    f\n</code></pre>\n");
+        assert_eq!(render("a    b", MarkdownConfig::default(), HtmlOptions::default()),
+                   "<p>a    b</p>\n");
+    }
+
+    #[test]
+    fn figures() {
+        let options = HtmlOptions::default().figures(true);
+        assert_eq!(render("![A *cat*](cat.png)\n", MarkdownConfig::default(), options),
+                   "<figure>\n<img src=\"cat.png\" alt=\"A cat\" />\n\
+                    <figcaption>A <em>cat</em></figcaption>\n</figure>\n");
+        assert_eq!(render("a ![b](c.png)\n\n![](d.png)\n", MarkdownConfig::default(), options),
+                   "<p>a <img src=\"c.png\" alt=\"b\" /></p>\n<p><img src=\"d.png\" alt=\"\" /></p>\n");
+        assert_eq!(render("![b][x]\n", MarkdownConfig::default(), options), "<p>![b][x]</p>\n");
+        assert_eq!(render("![b](c.png)\n", MarkdownConfig::default(), HtmlOptions::default()),
+                   "<p><img src=\"c.png\" alt=\"b\" /></p>\n");
+    }
+
+    #[test]
+    fn soft_breaks_as_spaces() {
+        let options = HtmlOptions::default().soft_break(SoftBreak::Space);
+        assert_eq!(render("a\n   b \nc\n\n- d\n  e\n", MarkdownConfig::default(), options),
+                   "<p>a b c</p>\n<ul>\n<li>d e</li>\n</ul>\n");
+        assert_eq!(render("a\n b", MarkdownConfig::default(), HtmlOptions::default()),
+                   "<p>a\n b</p>\n");
+    }
+
+    #[test]
+    fn gfm_tagfilter() {
+        let source = "<div><script>alert(1)</script></div>\n\na <SCRIPT src=x><scripts><title/>\n";
+        let config = MarkdownConfig::default().allow_html(true);
+        assert_eq!(render(source, config.clone(), HtmlOptions::default().gfm_tagfilter(true)),
+                   "<div>&lt;script>alert(1)&lt;/script></div>\n\
+                    <p>a &lt;SCRIPT src=x><scripts>&lt;title/></p>\n");
+        assert_eq!(render(source, config, HtmlOptions::default()),
+                   "<div><script>alert(1)</script></div>\n<p>a <SCRIPT src=x><scripts><title/></p>\n");
+    }
+
+    #[test]
+    fn single_block() {
+        let heading = Heading {
+            level: 2,
+            content: vec![Chunk("See ".to_string()),
+                          Link { text: Some(vec![Chunk("a".to_string())]), link: None, title: None,
+                                 id: Some("A".to_string()), reference: Some(FullReference) }],
+            attributes: None
+        };
+        let mut link_map = BTreeMap::new();
+        link_map.insert("a".to_string(),
+                        LinkDescription { id: "a".to_string(), link: "/x".to_string(), title: None });
+        assert_eq!(render_block_html(&heading, &link_map, HtmlOptions::default()),
+                   "<h2>See <a href=\"/x\">a</a></h2>\n");
+        assert_eq!(render_block_html(&heading, &BTreeMap::new(), HtmlOptions::default()),
+                   "<h2>See [a][A]</h2>\n");
+
+        let renderer = HtmlRenderer::new().with_options(HtmlOptions::default().max_output_size(10));
+        assert_eq!(renderer.try_render_single_block(&heading, &link_map),
+                   Err(RenderError::OutputTooLarge));
+    }
+
+    fn try_render(s: &str, config: MarkdownConfig, max_output_size: usize)
+                  -> Result<String, RenderError> {
+        let doc = MarkdownParser::from_str(s).with_config(config).read_all();
+        HtmlRenderer::new().with_options(HtmlOptions::default().max_output_size(max_output_size))
+            .try_render(&doc)
+    }
+
+    #[test]
+    fn max_output_size() {
+        // every reference copies the long destination into the output
+        let mut s = String::new();
+        for _ in 0..1000 { s.push_str("[a], "); }
+        s.push_str("\n\n[a]: /");
+        for _ in 0..1000 { s.push('x'); }
+        s.push('\n');
+        let config = MarkdownConfig::default();
+        assert!(try_render(s.as_slice(), config.clone(), 0).unwrap().len() > 500000);
+        assert_eq!(try_render(s.as_slice(), config, 10000), Err(RenderError::OutputTooLarge));
+    }
+
+    #[test]
+    fn max_output_size_within_a_block() {
+        let mut code = "```\n".to_string();
+        for _ in 0..1000 { code.push_str("<>&\""); }
+        code.push_str("\n```\n");
+        let config = MarkdownConfig::default();
+        assert!(try_render(code.as_slice(), config.clone(), 0).is_ok());
+        assert_eq!(try_render(code.as_slice(), config, 1000), Err(RenderError::OutputTooLarge));
+    }
+
+    #[test]
+    fn max_output_size_with_footnotes() {
+        let config = MarkdownConfig::default().footnotes(true);
+        let out = try_render(FOOTNOTES, config.clone(), 0).unwrap();
+        assert_eq!(try_render(FOOTNOTES, config.clone(), out.len()), Ok(out.clone()));
+        assert_eq!(try_render(FOOTNOTES, config, out.len() - 1), Err(RenderError::OutputTooLarge));
+    }
+
+    #[test]
+    fn to_html_with_config() {
+        assert_eq!(to_html("*a* ==b==", &MarkdownConfig::default().highlight(true)),
+                   "<p><em>a</em> <mark>b</mark></p>\n");
+    }
+}
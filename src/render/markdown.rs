@@ -0,0 +1,1024 @@
+use std::cmp;
+use std::mem;
+use std::cell::{Cell, RefCell};
+
+use tokens::*;
+
+use self::Indentation::*;
+
+// indentation of list item continuation lines
+#[derive(Copy, PartialEq, Eq, Debug)]
+pub enum Indentation {
+    MarkerAligned,  // aligned with the item content
+    Spaces(usize),  // the marker is padded to this width when possible
+    Tabs            // the marker is padded to a tab stop, four columns apart
+}
+
+#[derive(Copy)]
+pub struct ReserializeOptions {
+    pub wrap_width: usize,  // 0 means no wrapping
+    pub reference_links: bool,
+    pub indentation: Indentation
+}
+
+impl ReserializeOptions {
+    #[inline]
+    pub fn default() -> ReserializeOptions {
+        ReserializeOptions {
+            wrap_width: 0,
+            reference_links: false,
+            indentation: MarkerAligned
+        }
+    }
+}
+
+impl_setters! { ReserializeOptions;
+    wrap_width: usize,
+    reference_links: bool,
+    indentation: Indentation
+}
+
+struct ReferenceDefinition {
+    label: String,
+    link: String,
+    title: Option<String>
+}
+
+pub struct MarkdownRenderer {
+    options: ReserializeOptions,
+    references: RefCell<Vec<ReferenceDefinition>>,
+    // normalized labels referenced in the document, generated ones avoid them
+    document_labels: RefCell<Vec<String>>,
+    // nesting level of block quotes being rendered
+    quote_depth: Cell<usize>
+}
+
+// public methods
+impl MarkdownRenderer {
+    #[inline]
+    pub fn new() -> MarkdownRenderer {
+        MarkdownRenderer {
+            options: ReserializeOptions::default(),
+            references: RefCell::new(Vec::new()),
+            document_labels: RefCell::new(Vec::new()),
+            quote_depth: Cell::new(0)
+        }
+    }
+
+    #[inline]
+    pub fn with_options(mut self, options: ReserializeOptions) -> MarkdownRenderer {
+        self.options = options;
+        self
+    }
+
+    pub fn render(&self, doc: &Document) -> String {
+        self.references.borrow_mut().clear();
+        *self.document_labels.borrow_mut() = doc.links().iter().filter_map(|l| match l.id {
+            Some(id) if !id.is_empty() => Some(normalize_label(id)),
+            Some(_) => {
+                // collapsed reference, the label is the text itself
+                let mut buf = String::new();
+                match l.text {
+                    Some(text) => plain_text(&mut buf, text),
+                    None => {}
+                }
+                Some(normalize_label(buf.as_slice()))
+            }
+            None => None
+        }).collect();
+
+        let mut out = String::new();
+        self.render_document(&mut out, doc, self.options.wrap_width);
+
+        // reference definitions collected during rendering go to the bottom
+        let references = self.references.borrow();
+        if !references.is_empty() {
+            out.push('\n');
+        }
+        for r in references.iter() {
+            out.push_str(format!("[{}]: ", r.label).as_slice());
+            render_url(&mut out, r.link.as_slice());
+            render_title(&mut out, &r.title);
+            out.push('\n');
+        }
+
+        out
+    }
+}
+
+#[inline]
+pub fn render_markdown(doc: &Document) -> String {
+    MarkdownRenderer::new().render(doc)
+}
+
+// Accumulates rendered inline content. When wrapping is enabled, the content
+// is split into words which are joined into lines afterwards; unbreakable
+// pieces (code spans, links) always become a part of a single word.
+struct TextSink {
+    wrap: bool,
+    escape_pipes: bool,  // inside table cells
+    escape_line_starts: bool,  // in paragraphs, where text may start a block
+    words: Vec<Option<String>>,  // None is a hard break
+    current: String
+}
+
+impl TextSink {
+    fn new(wrap: bool) -> TextSink {
+        TextSink {
+            wrap: wrap,
+            escape_pipes: false,
+            escape_line_starts: false,
+            words: Vec::new(),
+            current: String::new()
+        }
+    }
+
+    // pushes a piece which cannot be broken
+    fn push(&mut self, s: &str) {
+        if self.wrap {
+            for c in s.chars() {
+                self.current.push(if c == '\n' { ' ' } else { c });
+            }
+        } else {
+            self.current.push_str(s);
+        }
+    }
+
+    // pushes a piece which can be broken on whitespace
+    fn push_text(&mut self, s: &str) {
+        if !self.wrap {
+            self.current.push_str(s);
+            return;
+        }
+
+        for c in s.chars() {
+            if c == ' ' || c == '\n' {
+                self.break_word();
+            } else {
+                self.current.push(c);
+            }
+        }
+    }
+
+    // whether the next pushed piece starts a line
+    fn at_line_start(&self) -> bool {
+        if !self.current.is_empty() {
+            return !self.wrap && self.current.as_slice().ends_with("\n");
+        }
+        match self.words.last() {
+            None | Some(&None) => true,
+            Some(&Some(_)) => false
+        }
+    }
+
+    fn break_word(&mut self) {
+        if !self.current.is_empty() {
+            let word = mem::replace(&mut self.current, String::new());
+            self.words.push(Some(word));
+        }
+    }
+
+    fn push_hard_break(&mut self) {
+        if self.wrap {
+            self.break_word();
+            self.words.push(None);
+        } else {
+            self.current.push_str("\\\n");
+        }
+    }
+
+    fn finish(mut self, width: usize) -> String {
+        if !self.wrap {
+            return self.current;
+        }
+        self.break_word();
+
+        let mut out = String::new();
+        let mut line_len = 0;
+        for word in self.words.iter() {
+            let word = match *word {
+                Some(ref word) => word,
+                None => {
+                    out.push_str("\\\n");
+                    line_len = 0;
+                    continue;
+                }
+            };
+            let word_len = word.as_slice().chars().count();
+            if line_len > 0 {
+                // do not break before words which would start a new block
+                if line_len + 1 + word_len > width && can_start_line(word.as_slice()) {
+                    out.push('\n');
+                    line_len = 0;
+                } else {
+                    out.push(' ');
+                    line_len += 1;
+                }
+            }
+            out.push_str(word.as_slice());
+            line_len += word_len;
+        }
+        out
+    }
+}
+
+// private methods
+impl MarkdownRenderer {
+    fn render_document(&self, out: &mut String, doc: &Document, width: usize) {
+        self.render_blocks(out, doc, width, false);
+    }
+
+    // blocks of tight list items are not separated with empty lines
+    fn render_blocks(&self, out: &mut String, doc: &Document, width: usize, tight: bool) {
+        // adjacent lists of the same kind would be merged when parsed again,
+        // so every other one of them uses alternative markers
+        let mut alternate = false;
+        for (i, block) in doc.iter().enumerate() {
+            if i > 0 && !tight { out.push('\n'); }
+            alternate = match (if i > 0 { doc.get(i-1) } else { None }, block) {
+                (Some(&OrderedList { .. }), &OrderedList { .. }) |
+                (Some(&UnorderedList { .. }), &UnorderedList { .. }) => !alternate,
+                _ => false
+            };
+            self.render_block(out, block, width, alternate);
+        }
+    }
+
+    fn render_block(&self, out: &mut String, block: &Block, width: usize, alternate: bool) {
+        match *block {
+            Heading { level, ref content, ref attributes } => {
+                for _ in 0..level { out.push('#'); }
+                // headings are never wrapped
+                let mut sink = TextSink::new(false);
+                self.render_text(&mut sink, content);
+                let content = sink.finish(0);
+                // empty headings consist of hashes only
+                if !content.is_empty() {
+                    out.push(' ');
+                    out.push_str(content.as_slice());
+                }
+                render_attributes(out, attributes);
+                out.push('\n');
+            }
+
+            BlockQuote(ref content) => {
+                let mut buf = String::new();
+                self.quote_depth.set(self.quote_depth.get() + 1);
+                self.render_document(&mut buf, content, shrink(width, 2));
+                self.quote_depth.set(self.quote_depth.get() - 1);
+                prefix_lines(out, buf.as_slice(), "> ", "> ");
+            }
+
+            BlockCode { ref tag, ref content, ref attributes } => {
+                // the fence should be longer than any run of fence characters in
+                // the content; backticks are not allowed in info strings of backtick fences
+                let fc = match *tag {
+                    Some(ref tag) if tag.as_slice().contains("`") => '~',
+                    _ => '`'
+                };
+                let n = cmp::max(3, longest_run(content.as_slice(), fc) + 1);
+                let fence: String = (0..n).map(|_| fc).collect();
+                out.push_str(fence.as_slice());
+                match *tag {
+                    Some(ref tag) => out.push_str(tag.as_slice()),
+                    None => {}
+                }
+                render_attributes(out, attributes);
+                out.push('\n');
+                out.push_str(content.as_slice());
+                if !content.is_empty() && !content.as_slice().ends_with("\n") {
+                    out.push('\n');
+                }
+                out.push_str(fence.as_slice());
+                out.push('\n');
+            }
+
+            OrderedList { start_index, ref items, tight } =>
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 && !tight { out.push('\n'); }
+                    let delim = if alternate { ')' } else { '.' };
+                    let marker = format!("{}{} ", start_index + i, delim);
+                    self.render_list_item(out, item, marker.as_slice(), width, tight);
+                },
+
+            UnorderedList { ref items, tight } =>
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 && !tight { out.push('\n'); }
+                    let marker = if alternate { "* " } else { "- " };
+                    self.render_list_item(out, item, marker, width, tight);
+                },
+
+            Paragraph(ref content) => {
+                let mut sink = TextSink::new(width > 0);
+                sink.escape_line_starts = true;
+                self.render_text(&mut sink, content);
+                out.push_str(sink.finish(width).as_slice());
+                out.push('\n');
+            }
+
+            HtmlBlock(ref content) => {
+                out.push_str(content.as_slice());
+                if !content.as_slice().ends_with("\n") {
+                    out.push('\n');
+                }
+            }
+
+            Table { ref alignments, ref header, ref rows } => {
+                self.render_table_row(out, header.as_slice());
+                out.push('|');
+                for alignment in alignments.iter() {
+                    out.push_str(match *alignment {
+                        AlignNone => " --- |",
+                        AlignLeft => " :-- |",
+                        AlignCenter => " :-: |",
+                        AlignRight => " --: |"
+                    });
+                }
+                out.push('\n');
+                for row in rows.iter() {
+                    self.render_table_row(out, row.as_slice());
+                }
+            }
+
+            // continuation lines are indented by four spaces
+            FootnoteDefinition { ref id, ref content } => {
+                let mut buf = String::new();
+                self.render_document(&mut buf, content, shrink(width, 4));
+                let label = format!("[^{}]: ", id);
+                if buf.is_empty() {
+                    out.push_str(label.as_slice().trim_right());
+                    out.push('\n');
+                } else {
+                    prefix_lines(out, buf.as_slice(), label.as_slice(), "    ");
+                }
+            }
+
+            // definition continuation lines are indented by four spaces
+            DefinitionList(ref items) =>
+                for (i, &(ref term, ref definitions)) in items.iter().enumerate() {
+                    if i > 0 { out.push('\n'); }
+                    let mut sink = TextSink::new(false);
+                    sink.escape_line_starts = true;
+                    self.render_text(&mut sink, term);
+                    out.push_str(sink.finish(0).as_slice());
+                    out.push('\n');
+                    for definition in definitions.iter() {
+                        let mut buf = String::new();
+                        self.render_document(&mut buf, definition, shrink(width, 4));
+                        if buf.is_empty() {
+                            out.push_str(":\n");
+                        } else {
+                            prefix_lines(out, buf.as_slice(), ":   ", "    ");
+                        }
+                    }
+                },
+
+            FrontMatter { format, ref content } => {
+                let fence = match format {
+                    Yaml => "---\n",
+                    Toml => "+++\n"
+                };
+                out.push_str(fence);
+                out.push_str(content.as_slice());
+                out.push_str(fence);
+            }
+
+            HorizontalRule => out.push_str("***\n")
+        }
+    }
+
+    fn render_list_item(&self, out: &mut String, item: &Document, marker: &str,
+                        width: usize, tight: bool) {
+        let spaces = |n: usize| (0..n).map(|_| ' ').collect::<String>();
+        let (marker, indent) = match self.options.indentation {
+            MarkerAligned => (marker.to_string(), spaces(marker.len())),
+            Spaces(n) => {
+                let marker = marker.trim_right();
+                // more than four spaces after the marker would start a code block
+                let n = cmp::min(cmp::max(n, marker.len() + 1), marker.len() + 4);
+                (format!("{}{}", marker, spaces(n - marker.len())), spaces(n))
+            }
+            Tabs => {
+                // tabs take exactly as many columns as the marker does; the `> `
+                // prefix of block quotes shifts tab stops, so spaces are used there
+                let marker = marker.trim_right();
+                let n = marker.len() / 4 + 1;
+                let indent = if self.quote_depth.get() > 0 {
+                    spaces(n * 4)
+                } else {
+                    (0..n).map(|_| '\t').collect::<String>()
+                };
+                (format!("{}{}", marker, spaces(n * 4 - marker.len())), indent)
+            }
+        };
+        let marker = marker.as_slice();
+
+        let mut buf = String::new();
+        self.render_blocks(&mut buf, item, shrink(width, marker.len()), tight);
+        if buf.is_empty() {
+            out.push_str(marker.trim_right());
+            out.push('\n');
+        } else {
+            prefix_lines(out, buf.as_slice(), marker, indent.as_slice());
+        }
+    }
+
+    // table rows are never wrapped
+    fn render_table_row(&self, out: &mut String, cells: &[Text]) {
+        out.push('|');
+        for cell in cells.iter() {
+            out.push(' ');
+            // pipes inside cells would split them, except in code spans
+            let mut sink = TextSink::new(false);
+            sink.escape_pipes = true;
+            self.render_text(&mut sink, cell);
+            out.push_str(sink.finish(0).as_slice());
+            out.push_str(" |");
+        }
+        out.push('\n');
+    }
+
+    fn render_text(&self, sink: &mut TextSink, text: &Text) {
+        for (i, inline) in text.iter().enumerate() {
+            self.render_inline(sink, inline, text.get(i+1));
+        }
+    }
+
+    fn render_inline(&self, sink: &mut TextSink, inline: &Inline, next: Option<&Inline>) {
+        match *inline {
+            LineBreak => sink.push_hard_break(),
+
+            Chunk(ref buf) => {
+                let mut escaped = String::new();
+                escape(&mut escaped, buf.as_slice());
+                if sink.escape_pipes {
+                    escaped = escaped.replace("|", "\\|");
+                }
+                // soft breaks start new lines unless the text is wrapped
+                if sink.escape_line_starts {
+                    escaped = escape_line_starts(escaped.as_slice(), sink.at_line_start(), !sink.wrap);
+                }
+                sink.push_text(escaped.as_slice());
+            }
+
+            Emphasis(ref content) => self.render_delimited(sink, "*", content),
+
+            MoreEmphasis(ref content) => self.render_delimited(sink, "**", content),
+
+            Highlight(ref content) => self.render_delimited(sink, "==", content),
+
+            Code(ref buf) => {
+                let fence: String = (0..longest_run(buf.as_slice(), '`') + 1).map(|_| '`').collect();
+                let padding = if buf.as_slice().starts_with("`") || buf.as_slice().ends_with("`") {
+                    " "
+                } else {
+                    ""
+                };
+                sink.push(fence.as_slice());
+                sink.push(padding);
+                // pipes are escaped in table cells even inside code spans
+                if sink.escape_pipes {
+                    sink.push(buf.replace("|", "\\|").as_slice());
+                } else {
+                    sink.push(buf.as_slice());
+                }
+                sink.push(padding);
+                sink.push(fence.as_slice());
+            }
+
+            // raw content is markdown source already
+            InlineHtml(ref buf) | Raw(ref buf) => sink.push(buf.as_slice()),
+
+            Link { text: None, ref link, .. } => {
+                sink.push("<");
+                sink.push(link.as_ref().map(|s| s.as_slice()).unwrap_or(""));
+                sink.push(">");
+            }
+
+            Link { text: Some(ref text), ref link, ref title, ref id, reference } => {
+                let text = self.render_unbreakable(text);
+                sink.push("[");
+                sink.push(text.as_slice());
+                sink.push("]");
+                self.render_destination(sink, text.as_slice(), link, title, id, reference, next);
+            }
+
+            // dimensions can only be specified inline
+            Image { ref alt, link: Some(ref link), ref title, width, height, .. }
+                    if width.is_some() || height.is_some() => {
+                let alt = self.render_unbreakable(alt);
+                let dimension = |d: Option<usize>| d.map(|d| d.to_string()).unwrap_or(String::new());
+                let mut buf = String::new();
+                render_url(&mut buf, link.as_slice());
+                buf.push_str(format!(" ={}x{}", dimension(width), dimension(height)).as_slice());
+                render_title(&mut buf, title);
+                sink.push(format!("![{}]({})", alt, buf).as_slice());
+            }
+
+            Image { ref alt, ref link, ref title, ref id, reference, .. } => {
+                let alt = self.render_unbreakable(alt);
+                sink.push("![");
+                sink.push(alt.as_slice());
+                sink.push("]");
+                self.render_destination(sink, alt.as_slice(), link, title, id, reference, next);
+            }
+
+            FootnoteReference(ref id) => {
+                sink.push("[^");
+                sink.push(id.as_slice());
+                sink.push("]");
+            }
+        }
+    }
+
+    fn render_delimited(&self, sink: &mut TextSink, delim: &str, content: &Text) {
+        sink.push(delim);
+        self.render_text(sink, content);
+        sink.push(delim);
+    }
+
+    fn render_unbreakable(&self, text: &Text) -> String {
+        let mut inner = TextSink::new(false);
+        self.render_text(&mut inner, text);
+        inner.finish(0)
+    }
+
+    fn render_destination(&self, sink: &mut TextSink, text: &str, link: &Option<String>,
+                          title: &Option<String>, id: &Option<String>,
+                          reference: Option<ReferenceKind>, next: Option<&Inline>) {
+        // empty id means collapsed reference, i.e. the label is the text itself
+        let label = id.as_ref().map(|id| if id.is_empty() { text } else { id.as_slice() });
+
+        match (link, label) {
+            // known destinations are rendered inline unless reference style is requested
+            (&Some(ref link), _) if !self.options.reference_links => {
+                sink.push("(");
+                let mut buf = String::new();
+                render_url(&mut buf, link.as_slice());
+                render_title(&mut buf, title);
+                sink.push(buf.as_slice());
+                sink.push(")");
+            }
+
+            (&Some(ref link), label) => {
+                let label = match label {
+                    Some(label) => label.to_string(),
+                    None => self.find_reference_label(link.as_slice(), title)
+                };
+                self.render_label(sink, text, label.as_slice(), next);
+                self.add_reference(label.as_slice(), link.as_slice(), title);
+            }
+
+            // undefined reference, written the way it was in the source
+            (&None, Some(label)) => match reference {
+                Some(FullReference) => {
+                    sink.push("[");
+                    sink.push(label);
+                    sink.push("]");
+                }
+                Some(CollapsedReference) => sink.push("[]"),
+                Some(ShortcutReference) => self.render_label(sink, text, text, next),
+                None => self.render_label(sink, text, label, next)
+            },
+
+            (&None, None) => sink.push("()")
+        }
+    }
+
+    fn render_label(&self, sink: &mut TextSink, text: &str, label: &str, next: Option<&Inline>) {
+        if text == label {
+            // shortcut reference is ambiguous if followed by something link-like
+            match next {
+                Some(&Chunk(ref buf)) if buf.as_slice().starts_with("(") ||
+                                         buf.as_slice().starts_with("[") =>
+                    sink.push("[]"),
+                _ => {}
+            }
+        } else {
+            sink.push("[");
+            sink.push(label);
+            sink.push("]");
+        }
+    }
+
+    // returns the label of already collected reference with the same destination
+    // or generates a new numeric one, unused in the document as well
+    fn find_reference_label(&self, link: &str, title: &Option<String>) -> String {
+        let references = self.references.borrow();
+        match references.iter().find(|r| r.link.as_slice() == link && r.title == *title) {
+            Some(r) => r.label.clone(),
+            None => {
+                let document_labels = self.document_labels.borrow();
+                let mut n = 1usize;
+                while references.iter().any(|r| normalize_label(r.label.as_slice()) == n.to_string()) ||
+                      document_labels.contains(&n.to_string()) {
+                    n += 1;
+                }
+                n.to_string()
+            }
+        }
+    }
+
+    fn add_reference(&self, label: &str, link: &str, title: &Option<String>) {
+        let mut references = self.references.borrow_mut();
+        let normalized = normalize_label(label);
+        if !references.iter().any(|r| normalize_label(r.label.as_slice()) == normalized) {
+            references.push(ReferenceDefinition {
+                label: label.to_string(),
+                link: link.to_string(),
+                title: title.clone()
+            });
+        }
+    }
+}
+
+// destinations which are empty or contain spaces are put in angle brackets;
+// characters which could end a destination or start a title are escaped
+fn render_url(out: &mut String, url: &str) {
+    let angle = url.is_empty() || url.chars().any(|c| c.is_whitespace());
+    if angle { out.push('<'); }
+    for c in url.chars() {
+        match c {
+            '\\' | '(' | ')' | '<' | '>' => {
+                out.push('\\');
+                out.push(c);
+            }
+            '"' | '\'' if angle => {
+                out.push('\\');
+                out.push(c);
+            }
+            c => out.push(c)
+        }
+    }
+    if angle { out.push('>'); }
+}
+
+// picks title delimiters which do not occur in the title, escaping
+// double quotes only when all of them do; backslashes are always escaped
+fn render_title(out: &mut String, title: &Option<String>) {
+    let title = match *title {
+        Some(ref title) => title.replace("\\", "\\\\"),
+        None => return
+    };
+    let title = title.as_slice();
+
+    out.push(' ');
+    if !title.contains("\"") {
+        out.push_str(format!("\"{}\"", title).as_slice());
+    } else if !title.contains("'") {
+        out.push_str(format!("'{}'", title).as_slice());
+    } else if !title.contains("(") && !title.contains(")") {
+        out.push_str(format!("({})", title).as_slice());
+    } else {
+        out.push_str(format!("\"{}\"", title.replace("\"", "\\\"")).as_slice());
+    }
+}
+
+fn render_attributes(out: &mut String, attributes: &Option<Attributes>) {
+    let attributes = match *attributes {
+        Some(ref attributes) => attributes,
+        None => return
+    };
+
+    let mut parts = Vec::new();
+    match attributes.id {
+        Some(ref id) => parts.push(format!("#{}", id)),
+        None => {}
+    }
+    for class in attributes.classes.iter() {
+        parts.push(format!(".{}", class));
+    }
+    for &(ref key, ref value) in attributes.data.iter() {
+        if value.as_slice().contains(" ") || value.is_empty() {
+            parts.push(format!("{}=\"{}\"", key, value));
+        } else {
+            parts.push(format!("{}={}", key, value));
+        }
+    }
+
+    out.push_str(" {");
+    out.push_str(parts.connect(" ").as_slice());
+    out.push_str("}");
+}
+
+fn prefix_lines(out: &mut String, buf: &str, first: &str, rest: &str) {
+    for (i, line) in buf.lines().enumerate() {
+        let prefix = if i == 0 { first } else { rest };
+        if line.is_empty() {
+            out.push_str(prefix.trim_right());
+        } else {
+            out.push_str(prefix);
+            out.push_str(line);
+        }
+        out.push('\n');
+    }
+}
+
+// width available for nested content, 0 still means no wrapping
+#[inline]
+fn shrink(width: usize, n: usize) -> usize {
+    if width == 0 { 0 } else if width > n { width - n } else { 1 }
+}
+
+// checks whether a line starting with this word won't be parsed as something else
+fn can_start_line(word: &str) -> bool {
+    let first = match word.chars().next() {
+        Some(c) => c,
+        None => return true
+    };
+    match first {
+        '#' | '>' | '<' => false,
+        // list markers, rules and setext underlines consist of marker characters only
+        '-' | '+' | '*' | '=' => !word.chars().all(|c| c == first),
+        '`' | '~' => !(word.starts_with("```") || word.starts_with("~~~")),
+        '0'...'9' => {
+            let rest = word.trim_left_matches(|c: char| c.is_digit(10));
+            !(rest.starts_with(".") || rest.starts_with(")"))
+        }
+        _ => true
+    }
+}
+
+fn longest_run(s: &str, c: char) -> usize {
+    let mut longest = 0;
+    let mut current = 0;
+    for cc in s.chars() {
+        if cc == c {
+            current += 1;
+            if current > longest { longest = current; }
+        } else {
+            current = 0;
+        }
+    }
+    longest
+}
+
+fn escape(out: &mut String, s: &str) {
+    for c in s.chars() {
+        match c {
+            '\\' | '`' | '*' | '_' | '[' | ']' => {
+                out.push('\\');
+                out.push(c);
+            }
+            c => out.push(c)
+        }
+    }
+}
+
+// escapes the first line of `s` if `first` is set and the following ones
+// if `rest` is, so that text at the beginning of a line cannot start a block
+fn escape_line_starts(s: &str, first: bool, rest: bool) -> String {
+    let mut out = String::new();
+    for (i, line) in s.split('\n').enumerate() {
+        if i > 0 { out.push('\n'); }
+        let at_line_start = if i == 0 { first } else { rest };
+        if at_line_start {
+            escape_block_start(&mut out, line);
+        } else {
+            out.push_str(line);
+        }
+    }
+    out
+}
+
+fn escape_block_start(out: &mut String, line: &str) {
+    let first = match line.chars().next() {
+        Some(c) => c,
+        None => return
+    };
+    let rest = &line[first.len_utf8()..];
+    let escaped = match first {
+        '#' | '>' | '<' => true,
+        // list markers, rules and setext underlines
+        '-' | '+' | '=' => rest.is_empty() || rest.starts_with(" ") || rest.starts_with("\t") ||
+                           line.chars().all(|c| c == first || c == ' '),
+        '~' => line.starts_with("~~~"),
+        '0'...'9' => {
+            // the delimiter of an ordered list marker is escaped instead
+            let digits = line.chars().take_while(|c| c.is_digit(10)).count();
+            let rest = &line[digits..];
+            if digits <= 9 && (rest.starts_with(".") || rest.starts_with(")")) &&
+               (rest.len() == 1 || rest[1..].starts_with(" ") || rest[1..].starts_with("\t")) {
+                out.push_str(&line[..digits]);
+                out.push('\\');
+                out.push_str(rest);
+                return;
+            }
+            false
+        }
+        _ => false
+    };
+    if escaped { out.push('\\'); }
+    out.push_str(line);
+}
+
+#[cfg(test)]
+mod tests {
+    use parser::MarkdownParser;
+    use render::render_html;
+    use tokens::*;
+    use super::*;
+
+    fn parse(s: &str) -> Document {
+        MarkdownParser::from_str(s).read_all()
+    }
+
+    fn reserialize(s: &str, options: ReserializeOptions) -> String {
+        MarkdownRenderer::new().with_options(options).render(&parse(s))
+    }
+
+    static LONG_PARAGRAPH: &'static str =
+        "Lorem ipsum dolor sit amet, consectetur adipiscing elit, sed do eiusmod tempor \
+         [a link with spaces](/url) incididunt ut labore et dolore magna aliqua \
+         `an_unbreakable_code_span_longer_than_the_width` ut enim ad minim veniam.\n";
+
+    #[test]
+    fn wrap() {
+        let out = reserialize(LONG_PARAGRAPH, ReserializeOptions::default().wrap_width(40));
+        assert!(out.lines().count() > 1);
+        for line in out.lines() {
+            assert!(line.chars().count() <= 40 || !line.contains(" "), "line too long: {}", line);
+        }
+        assert!(out.contains("[a link with spaces](/url)"));
+    }
+
+    #[test]
+    fn no_wrap() {
+        assert_eq!(reserialize(LONG_PARAGRAPH, ReserializeOptions::default()), LONG_PARAGRAPH);
+    }
+
+    #[test]
+    fn reference_links() {
+        let source = "[foo][ref] and [bar]\n\n[ref]: /url \"Title\"\n[bar]: /bar\n";
+        let out = reserialize(source, ReserializeOptions::default().reference_links(true));
+        assert_eq!(out, source);
+        assert_eq!(parse(out.as_slice()), parse(source));
+    }
+
+    #[test]
+    fn inline_links() {
+        assert_eq!(reserialize("[foo][ref]\n\n[ref]: /url \"Title\"\n", ReserializeOptions::default()),
+                   "[foo](/url \"Title\")\n");
+    }
+
+    #[test]
+    fn title_with_both_quotes() {
+        let source = "[a](/u \"x \\\"y\\\" 'z'\")\n";
+        let out = reserialize(source, ReserializeOptions::default());
+        assert_eq!(out, "[a](/u (x \"y\" 'z'))\n");
+        assert_eq!(parse(out.as_slice()), parse(source));
+    }
+
+    #[test]
+    fn title_delimiters() {
+        assert_eq!(reserialize("[a](/u 'x \"y\"')\n", ReserializeOptions::default()),
+                   "[a](/u 'x \"y\"')\n");
+        assert_eq!(reserialize("[a](/u \"x (y) \\\"z\\\" 'w'\")\n", ReserializeOptions::default()),
+                   "[a](/u \"x (y) \\\"z\\\" 'w'\")\n");
+    }
+
+    fn assert_round_trip(source: &str, options: ReserializeOptions) {
+        let doc = parse(source);
+        let out = MarkdownRenderer::new().with_options(options).render(&doc);
+        assert_eq!(parse(out.as_slice()), doc);
+        assert_eq!(MarkdownRenderer::new().with_options(options).render(&parse(out.as_slice())), out);
+    }
+
+    #[test]
+    fn nested_list_with_multi_paragraph_item() {
+        let source = "- a\n\n  b\n\n  - c\n  - d\n\n- e\n";
+        assert_eq!(reserialize(source, ReserializeOptions::default()), source);
+        assert_round_trip(source, ReserializeOptions::default());
+    }
+
+    #[test]
+    fn nested_ordered_list_with_multi_paragraph_item() {
+        assert_round_trip("1. a\n\n   b\n\n   1. c\n   2. d\n\n      e\n2. f\n",
+                          ReserializeOptions::default());
+        assert_round_trip("9. a\n10. b\n\n    c\n\n    - d\n", ReserializeOptions::default());
+    }
+
+    #[test]
+    fn two_space_indentation() {
+        let options = ReserializeOptions::default().indentation(Indentation::Spaces(2));
+        assert_eq!(reserialize("- a\n    - b\n      - c\n", options), "- a\n  - b\n    - c\n");
+        assert_round_trip("- a\n    - b\n      - c\n", options);
+    }
+
+    #[test]
+    fn indentation_is_at_least_marker_width() {
+        let options = ReserializeOptions::default().indentation(Indentation::Spaces(2));
+        assert_eq!(reserialize("1. a\n\n   b\n", options), "1. a\n\n   b\n");
+        let options = ReserializeOptions::default().indentation(Indentation::Spaces(4));
+        assert_eq!(reserialize("- a\n  - b\n", options), "-   a\n    -   b\n");
+        assert_round_trip("- a\n  - b\n", options);
+    }
+
+    #[test]
+    fn reserialization_is_deterministic() {
+        let mut source = String::new();
+        for i in 0..50 {
+            source.push_str(format!("[l{}] [x](/inline/{}) ", i, i % 7).as_slice());
+        }
+        source.push_str("\n\n");
+        for i in 0..50 {
+            source.push_str(format!("[l{}]: /ref/{}\n", i, 49 - i).as_slice());
+        }
+        let options = ReserializeOptions::default().reference_links(true);
+        let first = reserialize(source.as_slice(), options);
+        for _ in 0..5 {
+            assert_eq!(reserialize(source.as_slice(), options), first);
+        }
+        assert_eq!(reserialize(first.as_slice(), options), first);
+    }
+
+    #[test]
+    fn fence_longer_than_content_runs() {
+        let doc = vec![BlockCode {
+            tag: Some("md".to_string()),
+            content: "```\ncode\n````\n".to_string(),
+            attributes: None
+        }];
+        let out = MarkdownRenderer::new().render(&doc);
+        assert!(out.starts_with("`````md\n"));
+        assert_eq!(parse(out.as_slice()), doc);
+        assert_round_trip("~~~\n```\na\n```\n~~~\n", ReserializeOptions::default());
+    }
+
+    #[test]
+    fn tab_indentation() {
+        let options = ReserializeOptions::default().indentation(Indentation::Tabs);
+        assert_eq!(reserialize("- a\n  - b\n    - c\n", options), "-   a\n\t-   b\n\t\t-   c\n");
+        assert_round_trip("- a\n  - b\n    - c\n", options);
+        assert_round_trip("1. a\n\n   b\n\n   ```\n     code\n   ```\n", options);
+        assert_round_trip("10. a\n\n    b\n", options);
+        assert_eq!(reserialize("> - a\n>   - b\n", options), "> -   a\n>     -   b\n");
+        assert_round_trip("> - a\n>   - b\n", options);
+    }
+
+    #[test]
+    fn info_string_with_spaces() {
+        let source = "```python title=\"My Script\"\nprint(1)\n```\n";
+        assert_eq!(reserialize(source, ReserializeOptions::default()), source);
+        match parse(source).pop() {
+            Some(BlockCode { tag: Some(tag), .. }) => assert_eq!(tag, "python title=\"My Script\""),
+            other => panic!("expected a code block, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn block_starts_are_escaped() {
+        let sources = ["\\# a\n", "\\> a\n", "\\- a\n", "\\+ a\n", "1\\. a\n", "2\\) a\n", "\\<div>\n",
+                       "a\n\\- b\n", "a\n\\=\n", "a\n\\---\n", "a\\\n\\# b\n", "a # b - c\n"];
+        for source in sources.iter() {
+            assert_eq!(reserialize(*source, ReserializeOptions::default()), *source);
+            assert_round_trip(*source, ReserializeOptions::default());
+        }
+    }
+
+    #[test]
+    fn generated_labels_do_not_collide() {
+        let source = "[a](/x) and [b][1]\n\n[1]: /y\n";
+        let out = reserialize(source, ReserializeOptions::default().reference_links(true));
+        assert_eq!(out, "[a][2] and [b][1]\n\n[2]: /x\n[1]: /y\n");
+        assert_eq!(render_html(&parse(out.as_slice())), render_html(&parse(source)));
+    }
+
+    #[test]
+    fn labels_are_compared_normalized() {
+        let source = "[x][Foo] [y][foo]\n\n[foo]: /u\n";
+        let out = reserialize(source, ReserializeOptions::default().reference_links(true));
+        assert_eq!(out, "[x][Foo] [y][foo]\n\n[Foo]: /u\n");
+    }
+
+    #[test]
+    fn backslashes_in_titles() {
+        let options = ReserializeOptions::default();
+        let source = "[a](/u \"a\\\\*b\")\n";
+        assert_eq!(reserialize(source, options), source);
+        assert_round_trip(source, options);
+        let source = "[a](/u \"x \\\"y\\\" 'z' (w)\\\\\")\n";
+        assert_eq!(reserialize(source, options), source);
+        assert_round_trip(source, options);
+    }
+
+    #[test]
+    fn escaped_destinations() {
+        let options = ReserializeOptions::default();
+        assert_eq!(reserialize("[a](</my url>)\n", options), "[a](</my url>)\n");
+        assert_eq!(reserialize("[a](/x\\)y)\n", options), "[a](/x\\)y)\n");
+        assert_eq!(reserialize("[a](\\<x)\n", options), "[a](\\<x)\n");
+        assert_eq!(reserialize("[a]()\n", options), "[a](<>)\n");
+        for source in ["[a](</my url>)\n", "[a](/x\\)y \"t\")\n", "[a](\\<x)\n", "[a]()\n"].iter() {
+            assert_round_trip(*source, options);
+        }
+        assert_eq!(reserialize("[a](</b c>)\n", options.reference_links(true)),
+                   "[a][1]\n\n[1]: </b c>\n");
+    }
+
+    #[test]
+    fn undefined_references_keep_their_form() {
+        let source = "[a][a], [b][], [c] and ![d][d]\n";
+        assert_eq!(reserialize(source, ReserializeOptions::default()), source);
+    }
+}
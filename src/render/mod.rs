@@ -0,0 +1,8 @@
+pub use self::html::{HtmlRenderer, HtmlOptions, TableAlignment, VoidElements, FootnoteStyle,
+                     SoftBreak, RenderError, render_html, render_block_html, to_html};
+pub use self::markdown::{MarkdownRenderer, ReserializeOptions, Indentation, render_markdown};
+pub use self::plain::{PlainRenderer, PlainOptions, ImageText, render_plain};
+
+pub mod html;
+pub mod markdown;
+pub mod plain;
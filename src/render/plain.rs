@@ -0,0 +1,177 @@
+use tokens::*;
+
+use self::ImageText::*;
+
+#[derive(Copy, PartialEq, Eq, Debug)]
+pub enum ImageText {
+    AltText,      // alt text as is
+    Placeholder,  // `[image: alt]`
+    Omitted
+}
+
+#[derive(Copy)]
+pub struct PlainOptions {
+    pub images: ImageText
+}
+
+impl PlainOptions {
+    #[inline]
+    pub fn default() -> PlainOptions {
+        PlainOptions {
+            images: AltText
+        }
+    }
+}
+
+impl_setters! { PlainOptions;
+    images: ImageText
+}
+
+pub struct PlainRenderer {
+    options: PlainOptions
+}
+
+// public methods
+impl PlainRenderer {
+    #[inline]
+    pub fn new() -> PlainRenderer {
+        PlainRenderer {
+            options: PlainOptions::default()
+        }
+    }
+
+    #[inline]
+    pub fn with_options(mut self, options: PlainOptions) -> PlainRenderer {
+        self.options = options;
+        self
+    }
+
+    pub fn render(&self, doc: &Document) -> String {
+        let mut out = String::new();
+        self.render_document(&mut out, doc);
+        out
+    }
+}
+
+#[inline]
+pub fn render_plain(doc: &Document) -> String {
+    PlainRenderer::new().render(doc)
+}
+
+// private methods
+impl PlainRenderer {
+    fn render_document(&self, out: &mut String, doc: &Document) {
+        for (i, block) in doc.iter().enumerate() {
+            if i > 0 { out.push('\n'); }
+            self.render_block(out, block);
+        }
+    }
+
+    fn render_block(&self, out: &mut String, block: &Block) {
+        match *block {
+            Heading { ref content, .. } | Paragraph(ref content) => {
+                self.render_text(out, content);
+                out.push('\n');
+            }
+
+            // footnote contents stay where they are defined
+            BlockQuote(ref content) | FootnoteDefinition { ref content, .. } =>
+                self.render_document(out, content),
+
+            BlockCode { ref content, .. } => {
+                out.push_str(content.as_slice());
+                if !content.as_slice().ends_with("\n") {
+                    out.push('\n');
+                }
+            }
+
+            OrderedList { ref items, .. } | UnorderedList { ref items, .. } =>
+                for item in items.iter() {
+                    self.render_document(out, item);
+                },
+
+            // cells are separated with tabs
+            Table { ref header, ref rows, .. } =>
+                for row in Some(header).into_iter().chain(rows.iter()) {
+                    for (i, cell) in row.iter().enumerate() {
+                        if i > 0 { out.push('\t'); }
+                        self.render_text(out, cell);
+                    }
+                    out.push('\n');
+                },
+
+            DefinitionList(ref items) =>
+                for &(ref term, ref definitions) in items.iter() {
+                    self.render_text(out, term);
+                    out.push('\n');
+                    for definition in definitions.iter() {
+                        self.render_document(out, definition);
+                    }
+                },
+
+            HtmlBlock(_) | FrontMatter { .. } | HorizontalRule => {}
+        }
+    }
+
+    fn render_text(&self, out: &mut String, text: &Text) {
+        for inline in text.iter() {
+            self.render_inline(out, inline);
+        }
+    }
+
+    fn render_inline(&self, out: &mut String, inline: &Inline) {
+        match *inline {
+            LineBreak => out.push('\n'),
+
+            Chunk(ref buf) | Code(ref buf) | Raw(ref buf) => out.push_str(buf.as_slice()),
+
+            InlineHtml(_) | FootnoteReference(_) => {}
+
+            Emphasis(ref content) | MoreEmphasis(ref content) | Highlight(ref content) =>
+                self.render_text(out, content),
+
+            Link { text: Some(ref content), .. } => self.render_text(out, content),
+
+            Link { text: None, ref link, .. } =>
+                out.push_str(link.as_ref().map(|s| s.as_slice()).unwrap_or("")),
+
+            Image { ref alt, .. } => match self.options.images {
+                AltText => self.render_text(out, alt),
+                Placeholder => {
+                    out.push_str("[image: ");
+                    self.render_text(out, alt);
+                    out.push_str("]");
+                }
+                Omitted => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use parser::MarkdownParser;
+    use super::*;
+
+    fn render(s: &str, options: PlainOptions) -> String {
+        let doc = MarkdownParser::from_str(s).read_all();
+        PlainRenderer::new().with_options(options).render(&doc)
+    }
+
+    #[test]
+    fn image_alt_text() {
+        assert_eq!(render("a ![cat](cat.png) b\n", PlainOptions::default()), "a cat b\n");
+    }
+
+    #[test]
+    fn image_placeholder() {
+        let options = PlainOptions::default().images(ImageText::Placeholder);
+        assert_eq!(render("a ![cat](cat.png) b\n", options), "a [image: cat] b\n");
+    }
+
+    #[test]
+    fn image_omitted() {
+        let options = PlainOptions::default().images(ImageText::Omitted);
+        assert_eq!(render("a ![cat](cat.png) b\n", options), "a  b\n");
+    }
+}
@@ -1,13 +1,20 @@
-use std::collections::HashMap;
+use collections::BTreeMap;
+use std::mem;
 
 pub use self::Block::*;
 pub use self::Inline::*;
+pub use self::Alignment::*;
+pub use self::LinkKind::*;
+pub use self::ReferenceKind::*;
+pub use self::FrontMatterFormat::*;
 
 pub type Document = Vec<Block>;
 
 pub type Text = Vec<Inline>;
 
-pub type LinkMap = HashMap<String, LinkDescription>;
+// keys are normalized with `normalize_label`; the map is ordered to keep
+// anything iterating over it deterministic
+pub type LinkMap = BTreeMap<String, LinkDescription>;
 
 pub struct LinkDescription {
     pub id: String,
@@ -15,34 +22,185 @@ pub struct LinkDescription {
     pub title: Option<String>
 }
 
+// reference labels match case-insensitively with whitespace runs collapsed
+pub fn normalize_label(label: &str) -> String {
+    label.split(|c: char| c.is_whitespace()).filter(|w| !w.is_empty())
+        .map(|w| w.chars().map(|c| c.to_lowercase()).collect::<String>())
+        .collect::<Vec<_>>().connect(" ")
+}
+
+// normalized label if it is a valid link label, i.e. it is at most 999 characters
+// long, not blank and does not contain unescaped brackets
+pub fn normalize_link_label(label: &[u8]) -> Option<String> {
+    let mut escaping = false;
+    for &c in label.iter() {
+        match c {
+            _ if escaping => escaping = false,
+            b'\\' => escaping = true,
+            b'[' | b']' => return None,
+            _ => {}
+        }
+    }
+    let label = match ::std::str::from_utf8(label) {
+        Ok(label) if label.chars().count() <= 999 => normalize_label(label),
+        _ => return None
+    };
+    if label.is_empty() { None } else { Some(label) }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Attributes {
+    pub id: Option<String>,
+    pub classes: Vec<String>,
+    pub data: Vec<(String, String)>  // unknown keys
+}
+
+impl Attributes {
+    #[inline]
+    pub fn new() -> Attributes {
+        Attributes {
+            id: None,
+            classes: Vec::new(),
+            data: Vec::new()
+        }
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.id.is_none() && self.classes.is_empty() && self.data.is_empty()
+    }
+}
+
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub enum Block {
     Heading {
         level: usize,
-        content: Text
+        content: Text,
+        attributes: Option<Attributes>
     },
     
     BlockQuote(Document),
 
     BlockCode {
         tag: Option<String>,
-        content: String
+        content: String,
+        attributes: Option<Attributes>
     },
 
     OrderedList {
         start_index: usize,
-        items: Vec<Document>
+        items: Vec<Document>,
+        tight: bool
     },
 
     UnorderedList {
-        items: Vec<Document>
+        items: Vec<Document>,
+        tight: bool
     },
 
     Paragraph(Text),
 
+    HtmlBlock(String),
+
+    Table {
+        alignments: Vec<Alignment>,
+        header: Vec<Text>,
+        rows: Vec<Vec<Text>>
+    },
+
+    // `[^id]: content`, rendered by renderers wherever they put footnotes
+    FootnoteDefinition {
+        id: String,
+        content: Document
+    },
+
+    // terms with their definitions, e.g. `Term\n: definition`
+    DefinitionList(Vec<(Text, Vec<Document>)>),
+
+    // unparsed metadata at the beginning of the document
+    FrontMatter {
+        format: FrontMatterFormat,
+        content: String
+    },
+
     HorizontalRule
 }
 
+#[derive(Copy, PartialEq, Eq, Debug, Clone)]
+pub enum FrontMatterFormat {
+    Yaml,  // fenced with `---`
+    Toml   // fenced with `+++`
+}
+
+impl Block {
+    // first token of a code block info string, e.g. `rust` for `rust,ignore`
+    pub fn language(&self) -> Option<&str> {
+        match *self {
+            BlockCode { tag: Some(ref tag), .. } =>
+                match tag.as_slice().split(|c: char| c.is_whitespace() || c == ',').next() {
+                    Some(language) if !language.is_empty() => Some(language),
+                    _ => None
+                },
+            _ => None
+        }
+    }
+}
+
+pub trait DocumentOps {
+    // format and content of the `FrontMatter` block, if the document starts with it
+    fn front_matter(&self) -> Option<(FrontMatterFormat, &str)>;
+    // blocks after the front matter
+    fn body(&self) -> &[Block];
+    // normalized labels of references without definitions, in document order
+    fn unresolved_references(&self) -> Vec<String>;
+}
+
+impl DocumentOps for Document {
+    fn front_matter(&self) -> Option<(FrontMatterFormat, &str)> {
+        match self.first() {
+            Some(&FrontMatter { format, ref content }) => Some((format, content.as_slice())),
+            _ => None
+        }
+    }
+
+    fn body(&self) -> &[Block] {
+        match self.front_matter() {
+            Some(_) => &self[1..],
+            None => self.as_slice()
+        }
+    }
+
+    fn unresolved_references(&self) -> Vec<String> {
+        let mut result = Vec::new();
+        for l in self.links().iter().filter(|l| l.kind == ReferenceLink && l.link.is_none()) {
+            let label = match l.id {
+                Some(id) if !id.is_empty() => normalize_label(id),
+                _ => normalize_label(plain_alt(l).as_slice())
+            };
+            if !result.contains(&label) {
+                result.push(label);
+            }
+        }
+        result
+    }
+}
+
+#[derive(Copy, PartialEq, Eq, Debug, Clone)]
+pub enum Alignment {
+    AlignNone,
+    AlignLeft,
+    AlignCenter,
+    AlignRight
+}
+
+// how a reference link was written, needed to restore undefined references
+#[derive(Copy, PartialEq, Eq, Debug, Clone)]
+pub enum ReferenceKind {
+    FullReference,       // `[text][label]`
+    CollapsedReference,  // `[text][]`
+    ShortcutReference    // `[text]`
+}
+
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub enum Inline{
     LineBreak,
@@ -53,20 +211,53 @@ pub enum Inline{
 
     MoreEmphasis(Text),
 
+    Highlight(Text),
+
     Code(String),
 
+    InlineHtml(String),
+
     Link {
         text: Option<Text>,  // None for automatic links
         link: Option<String>,
         title: Option<String>,
-        id: Option<String>
+        id: Option<String>,
+        reference: Option<ReferenceKind>  // None for inline and automatic links
     },
 
     Image {
         alt: Text,
         link: Option<String>,
         title: Option<String>,
-        id: Option<String>
+        id: Option<String>,
+        reference: Option<ReferenceKind>,
+        // from `=WxH` after the destination, if image dimensions are enabled
+        width: Option<usize>,
+        height: Option<usize>
+    },
+
+    FootnoteReference(String),  // id as written
+
+    // unparsed inline content when inline parsing is skipped; owned like the
+    // rest of the tree, which does not borrow from the source buffer
+    Raw(String)
+}
+
+// collects only textual content of inlines, e.g. for image alt attributes or heading ids
+pub fn plain_text(out: &mut String, text: &Text) {
+    for inline in text.iter() {
+        match *inline {
+            LineBreak => out.push('\n'),
+            Chunk(ref buf) | Code(ref buf) | Raw(ref buf) => out.push_str(buf.as_slice()),
+            InlineHtml(_) => {}
+            Emphasis(ref content) | MoreEmphasis(ref content) | Highlight(ref content) =>
+                plain_text(out, content),
+            Link { text: Some(ref content), .. } => plain_text(out, content),
+            Link { text: None, ref link, .. } =>
+                out.push_str(link.as_ref().map(|s| s.as_slice()).unwrap_or("")),
+            Image { ref alt, .. } => plain_text(out, alt),
+            FootnoteReference(_) => {}
+        }
     }
 }
 
@@ -85,9 +276,10 @@ pub trait FixLinks {
 impl FixLinks for Block {
     fn fix_links(&mut self, link_map: &LinkMap) {
         match *self {
-            BlockQuote(ref mut content) => content.fix_links(link_map),
+            BlockQuote(ref mut content) | FootnoteDefinition { ref mut content, .. } =>
+                content.fix_links(link_map),
 
-            OrderedList { ref mut items, .. } | UnorderedList { ref mut items } =>
+            OrderedList { ref mut items, .. } | UnorderedList { ref mut items, .. } =>
                 for item in items.iter_mut() {
                     item.fix_links(link_map);
                 },
@@ -95,6 +287,19 @@ impl FixLinks for Block {
             Paragraph(ref mut content) | Heading { ref mut content, .. } => 
                 content.fix_links(link_map),
 
+            Table { ref mut header, ref mut rows, .. } =>
+                for cell in header.iter_mut().chain(rows.iter_mut().flat_map(|r| r.iter_mut())) {
+                    cell.fix_links(link_map);
+                },
+
+            DefinitionList(ref mut items) =>
+                for &mut (ref mut term, ref mut definitions) in items.iter_mut() {
+                    term.fix_links(link_map);
+                    for definition in definitions.iter_mut() {
+                        definition.fix_links(link_map);
+                    }
+                },
+
             _ => {}
         }
     }
@@ -119,24 +324,386 @@ impl FixLinks for Text {
 impl FixLinks for Inline {
     fn fix_links(&mut self, link_map: &LinkMap) {
         match *self {
-            Emphasis(ref mut content) | MoreEmphasis(ref mut content) =>
+            Emphasis(ref mut content) | MoreEmphasis(ref mut content) |
+            Highlight(ref mut content) =>
+                content.fix_links(link_map),
+
+            Link { ref mut link, ref mut title, id: Some(ref id), ref mut text, .. } => {
+                match *text {
+                    Some(ref mut text) => text.fix_links(link_map),
+                    None => {}
+                }
+                resolve_link(link_map, link, title, id, text.as_ref());
+            }
+
+            Image { ref mut link, ref mut title, id: Some(ref id), ref mut alt, .. } => {
+                alt.fix_links(link_map);
+                resolve_link(link_map, link, title, id, Some(&*alt));
+            }
+
+            Link { text: Some(ref mut content), .. } | Image { alt: ref mut content, .. } =>
                 content.fix_links(link_map),
 
-            Link { ref mut link, ref mut title, id: Some(ref id), .. } => {
-                match link_map.get(id) {
-                    Some(ld) => {
-                        if link.is_none() {
-                            *link = Some(ld.link.clone());
-                        }
-                        if title.is_none() && ld.title.is_none() {
-                            *title = ld.title.clone();
-                        }
+            _ => {}
+        }
+    }
+}
+
+fn resolve_link(link_map: &LinkMap, link: &mut Option<String>, title: &mut Option<String>,
+                id: &str, text: Option<&Text>) {
+    // empty id means collapsed reference, i.e. the label is the text itself
+    let label = if id.is_empty() {
+        let mut buf = String::new();
+        match text {
+            Some(text) => plain_text(&mut buf, text),
+            None => {}
+        }
+        normalize_label(buf.as_slice())
+    } else {
+        normalize_label(id)
+    };
+
+    match link_map.get(&label) {
+        Some(ld) => {
+            if link.is_none() {
+                *link = Some(ld.link.clone());
+            }
+            if title.is_none() {
+                *title = ld.title.clone();
+            }
+        }
+        None => {}
+    }
+}
+
+pub trait MergeChunks {
+    fn merge_chunks(&mut self);
+}
+
+impl MergeChunks for Block {
+    fn merge_chunks(&mut self) {
+        match *self {
+            BlockQuote(ref mut content) | FootnoteDefinition { ref mut content, .. } =>
+                content.merge_chunks(),
+
+            OrderedList { ref mut items, .. } | UnorderedList { ref mut items, .. } =>
+                for item in items.iter_mut() {
+                    item.merge_chunks();
+                },
+
+            Paragraph(ref mut content) | Heading { ref mut content, .. } =>
+                content.merge_chunks(),
+
+            Table { ref mut header, ref mut rows, .. } =>
+                for cell in header.iter_mut().chain(rows.iter_mut().flat_map(|r| r.iter_mut())) {
+                    cell.merge_chunks();
+                },
+
+            DefinitionList(ref mut items) =>
+                for &mut (ref mut term, ref mut definitions) in items.iter_mut() {
+                    term.merge_chunks();
+                    for definition in definitions.iter_mut() {
+                        definition.merge_chunks();
                     }
+                },
+
+            _ => {}
+        }
+    }
+}
+
+impl MergeChunks for Document {
+    fn merge_chunks(&mut self) {
+        for b in self.iter_mut() {
+            b.merge_chunks();
+        }
+    }
+}
+
+impl MergeChunks for Text {
+    fn merge_chunks(&mut self) {
+        let old = mem::replace(self, Vec::new());
+        for mut inline in old.into_iter() {
+            inline.merge_chunks();
+
+            let merged = match (self.last_mut(), &inline) {
+                (_, &Chunk(ref buf)) if buf.is_empty() => true,
+                (Some(&mut Chunk(ref mut last)), &Chunk(ref buf)) => {
+                    last.push_str(buf.as_slice());
+                    true
+                }
+                _ => false
+            };
+            if !merged {
+                self.push(inline);
+            }
+        }
+    }
+}
+
+impl MergeChunks for Inline {
+    fn merge_chunks(&mut self) {
+        match *self {
+            Emphasis(ref mut content) | MoreEmphasis(ref mut content) |
+            Highlight(ref mut content) | Image { alt: ref mut content, .. } |
+            Link { text: Some(ref mut content), .. } =>
+                content.merge_chunks(),
+
+            _ => {}
+        }
+    }
+}
+
+#[derive(Copy, PartialEq, Eq, Debug)]
+pub enum LinkKind {
+    InlineLink,
+    ReferenceLink,
+    AutoLink
+}
+
+#[derive(Copy, PartialEq, Eq, Debug)]
+pub struct LinkInfo<'a> {
+    pub kind: LinkKind,
+    pub image: bool,
+    pub link: Option<&'a str>,  // None for unresolved references
+    pub title: Option<&'a str>,
+    pub id: Option<&'a str>,
+    pub text: Option<&'a Text>  // link text or image alt, None for autolinks
+}
+
+// title with backslash escapes and entities resolved; the parser already
+// resolves them, so this is the title itself
+pub fn plain_title(info: &LinkInfo) -> String {
+    info.title.map(|t| t.to_string()).unwrap_or(String::new())
+}
+
+// link text or image alt as plain text
+pub fn plain_alt(info: &LinkInfo) -> String {
+    let mut result = String::new();
+    match info.text {
+        Some(text) => plain_text(&mut result, text),
+        None => {}
+    }
+    result
+}
+
+pub trait CollectLinks {
+    // all links and images in document order
+    fn links(&self) -> Vec<LinkInfo> {
+        let mut result = Vec::new();
+        self.collect_links(&mut result);
+        result
+    }
+
+    fn collect_links<'a>(&'a self, result: &mut Vec<LinkInfo<'a>>);
+}
+
+impl CollectLinks for Block {
+    fn collect_links<'a>(&'a self, result: &mut Vec<LinkInfo<'a>>) {
+        match *self {
+            BlockQuote(ref content) | FootnoteDefinition { ref content, .. } =>
+                content.collect_links(result),
+
+            OrderedList { ref items, .. } | UnorderedList { ref items, .. } =>
+                for item in items.iter() {
+                    item.collect_links(result);
+                },
+
+            Paragraph(ref content) | Heading { ref content, .. } =>
+                content.collect_links(result),
+
+            Table { ref header, ref rows, .. } =>
+                for cell in header.iter().chain(rows.iter().flat_map(|r| r.iter())) {
+                    cell.collect_links(result);
+                },
+
+            DefinitionList(ref items) =>
+                for &(ref term, ref definitions) in items.iter() {
+                    term.collect_links(result);
+                    for definition in definitions.iter() {
+                        definition.collect_links(result);
+                    }
+                },
+
+            _ => {}
+        }
+    }
+}
+
+impl CollectLinks for Document {
+    fn collect_links<'a>(&'a self, result: &mut Vec<LinkInfo<'a>>) {
+        for b in self.iter() {
+            b.collect_links(result);
+        }
+    }
+}
+
+impl CollectLinks for Text {
+    fn collect_links<'a>(&'a self, result: &mut Vec<LinkInfo<'a>>) {
+        for i in self.iter() {
+            i.collect_links(result);
+        }
+    }
+}
+
+impl CollectLinks for Inline {
+    fn collect_links<'a>(&'a self, result: &mut Vec<LinkInfo<'a>>) {
+        fn info<'a>(image: bool, text: Option<&'a Text>, link: &'a Option<String>,
+                    title: &'a Option<String>, id: &'a Option<String>) -> LinkInfo<'a> {
+            LinkInfo {
+                kind: if text.is_none() { AutoLink } else if id.is_some() { ReferenceLink } else { InlineLink },
+                image: image,
+                link: link.as_ref().map(|s| s.as_slice()),
+                title: title.as_ref().map(|s| s.as_slice()),
+                id: id.as_ref().map(|s| s.as_slice()),
+                text: text
+            }
+        }
+
+        match *self {
+            Emphasis(ref content) | MoreEmphasis(ref content) |
+            Highlight(ref content) =>
+                content.collect_links(result),
+
+            Link { ref text, ref link, ref title, ref id, .. } => {
+                result.push(info(false, text.as_ref(), link, title, id));
+                match *text {
+                    Some(ref text) => text.collect_links(result),
                     None => {}
                 }
             }
-            
+
+            Image { ref alt, ref link, ref title, ref id, .. } => {
+                result.push(info(true, Some(alt), link, title, id));
+                alt.collect_links(result);
+            }
+
             _ => {}
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use parser::MarkdownParser;
+    use super::*;
+
+    fn parse(s: &str) -> Document {
+        MarkdownParser::from_str(s).read_all()
+    }
+
+    fn chunk(s: &str) -> Inline { Chunk(s.to_string()) }
+
+    #[test]
+    fn escapes_are_merged_into_one_chunk() {
+        assert_eq!(parse("a\\&amp;b"), vec![Paragraph(vec![chunk("a&amp;b")])]);
+    }
+
+    #[test]
+    fn merge_chunks() {
+        let mut text = vec![chunk("a"), chunk(""), chunk("b"), Emphasis(vec![chunk("c"), chunk("d")]),
+                            chunk("e")];
+        text.merge_chunks();
+        assert_eq!(text, vec![chunk("ab"), Emphasis(vec![chunk("cd")]), chunk("e")]);
+    }
+
+    #[test]
+    fn code_block_language() {
+        let doc = parse("```rust,ignore\nx\n```\n");
+        assert_eq!(doc, vec![BlockCode {
+            tag: Some("rust,ignore".to_string()),
+            content: "x\n".to_string(),
+            attributes: None
+        }]);
+        assert_eq!(doc[0].language(), Some("rust"));
+        assert_eq!(parse("```python {.highlight}\nx\n```\n")[0].language(), Some("python"));
+        assert_eq!(parse("```\nx\n```\n")[0].language(), None);
+    }
+
+    #[test]
+    fn links() {
+        let doc = parse("[a](/inline) [b][ref] <https://auto.example>\n\n[ref]: /reference\n");
+        let links = doc.links();
+        assert_eq!(links.iter().map(|l| l.kind).collect::<Vec<_>>(),
+                   vec![InlineLink, ReferenceLink, AutoLink]);
+        assert_eq!(links.iter().map(|l| l.link).collect::<Vec<_>>(),
+                   vec![Some("/inline"), Some("/reference"), Some("https://auto.example")]);
+    }
+
+    #[test]
+    fn plain_title_and_alt() {
+        let doc = parse("![a \\*b\\* *c*](/x \"t \\\"q\\\" &lt;\")");
+        let links = doc.links();
+        assert_eq!(links.len(), 1);
+        assert_eq!(plain_alt(&links[0]), "a *b* c");
+        assert_eq!(plain_title(&links[0]), "t \"q\" <");
+    }
+
+    #[test]
+    fn front_matter_and_body() {
+        use parser::MarkdownConfig;
+        use render::render_html;
+
+        let config = MarkdownConfig::default().front_matter(Some(Yaml));
+        let doc = MarkdownParser::from_str("---\ntitle: a\n---\n# b\n").with_config(config).read_all();
+        assert_eq!(doc.front_matter(), Some((Yaml, "title: a\n")));
+        assert_eq!(doc.body().len(), 1);
+        assert!(match doc.body()[0] { Heading { .. } => true, _ => false });
+        assert_eq!(render_html(&doc), "<h1>b</h1>\n");
+
+        let doc = parse("# b\n");
+        assert_eq!(doc.front_matter(), None);
+        assert_eq!(doc.body(), doc.as_slice());
+    }
+
+    #[test]
+    fn link_labels() {
+        assert_eq!(normalize_link_label(b"Foo"), Some("foo".to_string()));
+        assert_eq!(normalize_link_label(b"  Foo \n\t bar  "), Some("foo bar".to_string()));
+        assert_eq!(normalize_link_label(b"a\\[b"), Some("a\\[b".to_string()));
+        assert_eq!(normalize_link_label(b"a[b"), None);
+        assert_eq!(normalize_link_label(b" \n "), None);
+
+        let long: Vec<u8> = ::std::iter::repeat(b'a').take(1000).collect();
+        assert_eq!(normalize_link_label(long.as_slice()), None);
+        assert!(normalize_link_label(&long[..999]).is_some());
+
+        // the limit is in characters, not bytes
+        let label: String = ::std::iter::repeat('字').take(400).collect();
+        assert_eq!(normalize_link_label(label.as_bytes()), Some(label.clone()));
+        let doc = parse(format!("[{0}]\n\n[{0}]: /u\n", label).as_slice());
+        assert_eq!(doc.links()[0].link, Some("/u"));
+        let long: String = ::std::iter::repeat('字').take(1000).collect();
+        assert_eq!(normalize_link_label(long.as_bytes()), None);
+    }
+
+    #[test]
+    fn documents_outlive_their_source() {
+        use std::collections::HashMap;
+
+        fn owned<T: 'static>(t: T) -> T { t }
+
+        let mut cache = HashMap::new();
+        for name in ["a", "b"].iter() {
+            let source = format!("# {}\n\n*{}*\n", name, name);
+            let doc = owned(MarkdownParser::from_str(source.as_slice()).read_all());
+            drop(source);
+            cache.insert(name.to_string(), doc);
+        }
+        assert_eq!(cache.get(&"b".to_string()).unwrap()[1],
+                   Paragraph(vec![Emphasis(vec![chunk("b")])]));
+    }
+
+    #[test]
+    fn label_normalization() {
+        assert_eq!(normalize_label(" Foo\tBAR\n\nbaz "), "foo bar baz");
+        assert_eq!(normalize_label("a\u{a0}\u{2003}b"), "a b");
+    }
+
+    #[test]
+    fn unresolved_references() {
+        let doc = parse("[a], [Missing][], ![img][Other], [b][missing], [x](/inline)\n\n[a]: /a\n");
+        assert_eq!(doc.unresolved_references(), vec!["missing".to_string(), "other".to_string()]);
+        assert!(parse("[a]\n\n[a]: /a\n").unresolved_references().is_empty());
+    }
+}
@@ -1,4 +1,44 @@
-use std::cell::Cell;
+use core::cell::Cell;
+
+// without `std` there is no logging
+#[cfg(not(feature = "std"))]
+macro_rules! debug {
+    ($($arg:tt)*) => (())
+}
+
+macro_rules! impl_setters {
+    ($target:ident; $($name:ident : $t:ty),+) => ($(
+        impl $target {
+            pub fn $name(mut self, value: $t) -> $target {
+                self.$name = value;
+                self
+            }
+        }
+    )+)
+}
+
+macro_rules! impl_builder_setters {
+    ($target:ident.$field:ident; $($name:ident : $t:ty),+) => ($(
+        impl $target {
+            pub fn $name(mut self, value: $t) -> $target {
+                self.$field.$name = value;
+                self
+            }
+        }
+    )+)
+}
+
+// converts parsed bytes to a string; NUL characters are replaced with
+// U+FFFD as required by CommonMark
+// TODO: handle UTF-8 decoding error
+pub fn to_text(buf: &[u8]) -> String {
+    let s = ::std::str::from_utf8(buf).unwrap();
+    if s.contains_char('\0') {
+        s.replace("\0", "\u{FFFD}")
+    } else {
+        s.to_string()
+    }
+}
 
 pub trait CellOps<T> {
     fn modify<F: FnOnce(T) -> T>(&self, f: F);
@@ -13,6 +53,7 @@ impl<T: Copy> CellOps<T> for Cell<T> {
 pub trait CharOps {
     fn is_emphasis(self) -> bool;
     fn is_code(self) -> bool;
+    fn is_highlight(self) -> bool;
     fn is_space(self) -> bool;
     fn is_numeric(self) -> bool;
 }
@@ -26,6 +67,10 @@ impl CharOps for u8 {
         self == b'`'
     }
 
+    fn is_highlight(self) -> bool {
+        self == b'='
+    }
+
     fn is_space(self) -> bool {
         self == b' ' || self == b'\n'
     }
@@ -101,3 +146,102 @@ impl<'a> ByteSliceOps<'a> for &'a [u8] {
     }
 
 }
+
+// resolves backslash escapes and entities
+pub fn unescape(s: &str) -> String {
+    let mut result = String::new();
+    let mut rest = s;
+    while !rest.is_empty() {
+        let c = rest.char_at(0);
+        if c == '\\' && rest.len() > 1 && is_ascii_punctuation(rest.char_at(1)) {
+            result.push(rest.char_at(1));
+            rest = &rest[2..];
+            continue;
+        }
+        if c == '&' {
+            match rest.find(';').and_then(|end| decode_entity(&rest[1..end]).map(|d| (end, d))) {
+                Some((end, decoded)) => {
+                    result.push(decoded);
+                    rest = &rest[end+1..];
+                    continue;
+                }
+                None => {}
+            }
+        }
+        result.push(c);
+        rest = &rest[c.len_utf8()..];
+    }
+    result
+}
+
+// scheme of an absolute url, e.g. `https` or `mailto`
+pub fn url_scheme(url: &str) -> Option<&str> {
+    match url.find(':') {
+        Some(idx) if idx > 0 => {
+            let scheme = &url[..idx];
+            if scheme.chars().next().unwrap().is_alphabetic() &&
+                scheme.chars().all(|c| c.is_alphanumeric() || c == '+' || c == '.' || c == '-') {
+                Some(scheme)
+            } else {
+                None
+            }
+        }
+        _ => None
+    }
+}
+
+// absolute urls have a scheme or are protocol-relative
+pub fn is_absolute_url(url: &str) -> bool {
+    url.starts_with("//") || url_scheme(url).is_some()
+}
+
+// CommonMark allows escaping any ASCII punctuation character
+#[inline]
+pub fn is_ascii_punctuation(c: char) -> bool {
+    c > ' ' && c < '\x7f' && !c.is_alphanumeric()
+}
+
+// decodes numeric and the most common named entities, `name` is without `&` and `;`
+fn decode_entity(name: &str) -> Option<char> {
+    if name.starts_with("#x") || name.starts_with("#X") {
+        return ::std::num::from_str_radix::<u32>(&name[2..], 16).ok().and_then(::std::char::from_u32);
+    }
+    if name.starts_with("#") {
+        return name[1..].parse::<u32>().ok().and_then(::std::char::from_u32);
+    }
+    match name {
+        "amp" => Some('&'),
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        "nbsp" => Some('\u{a0}'),
+        "copy" => Some('\u{a9}'),
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use parser::MarkdownParser;
+    use tokens::*;
+    use super::*;
+
+    #[test]
+    fn nul_is_replaced() {
+        assert_eq!(to_text(b"a\x00b"), "a\u{FFFD}b");
+        assert_eq!(MarkdownParser::new(b"a\x00b").read_all(),
+                   vec![Paragraph(vec![Chunk("a\u{FFFD}b".to_string())])]);
+    }
+
+    #[test]
+    fn url_schemes() {
+        assert_eq!(url_scheme("https://x.org"), Some("https"));
+        assert_eq!(url_scheme("mailto:a@b.c"), Some("mailto"));
+        assert_eq!(url_scheme("/a:b"), None);
+        assert_eq!(url_scheme("1a:b"), None);
+        assert!(is_absolute_url("//cdn.org/x"));
+        assert!(is_absolute_url("svn+ssh://x"));
+        assert!(!is_absolute_url("rel/path"));
+    }
+}